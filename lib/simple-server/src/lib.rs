@@ -26,10 +26,12 @@
 #[macro_use]
 extern crate log;
 
+extern crate base64;
 extern crate http;
 extern crate httparse;
 extern crate num_cpus;
 extern crate scoped_threadpool;
+extern crate sha1_smol;
 extern crate time;
 
 pub use http::method::Method;
@@ -42,25 +44,40 @@ use scoped_threadpool::Pool;
 
 use std::env;
 use std::fmt;
-use std::fs::File;
 use std::io::prelude::*;
 use std::net::{TcpListener, TcpStream};
-use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use std::borrow::Borrow;
 
 mod error;
 mod parsing;
 mod request;
+mod shared;
+mod shutdown;
+mod static_files;
+mod stats;
+mod stream;
+pub mod websocket;
 
 pub use error::Error;
+pub use shared::shared;
+pub use shutdown::Shutdown;
+pub use stats::{RouteStats, Stats, LATENCY_BUCKETS};
+pub use stream::stream;
+
+// How long responses in progress may take to finish after a shutdown.
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 5;
 
 pub type ResponseResult = Result<Response<Vec<u8>>, Error>;
 
 pub type Handler =
     Box<dyn Fn(Request<Vec<u8>>, ResponseBuilder) -> ResponseResult + 'static + Send + Sync>;
 
+pub type RouteLabel = Box<dyn Fn(&Request<Vec<u8>>) -> String + 'static + Send + Sync>;
+
 /// A web server.
 ///
 /// This is the core type of this crate, and is used to create a new
@@ -69,14 +86,19 @@ pub struct Server {
     handler: Handler,
     timeout: Option<Duration>,
     static_directory: Option<PathBuf>,
+    pool_size: Option<u32>,
+    shutdown: Shutdown,
+    shutdown_timeout: Duration,
+    stats: Stats,
+    route_label: Option<RouteLabel>,
 }
 
 impl fmt::Debug for Server {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "Server {{ timeout: {:?}, static_directory: {:?} }}",
-            self.timeout, self.static_directory
+            "Server {{ timeout: {:?}, static_directory: {:?}, pool_size: {:?} }}",
+            self.timeout, self.static_directory, self.pool_size
         )
     }
 }
@@ -117,6 +139,11 @@ impl Server {
             handler: Box::new(handler),
             timeout: None,
             static_directory: Some(PathBuf::from("public")),
+            pool_size: None,
+            shutdown: Shutdown::default(),
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            stats: Stats::default(),
+            route_label: None,
         }
     }
 
@@ -157,6 +184,11 @@ impl Server {
             handler: Box::new(handler),
             timeout: Some(timeout),
             static_directory: Some(PathBuf::from("public")),
+            pool_size: None,
+            shutdown: Shutdown::default(),
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            stats: Stats::default(),
+            route_label: None,
         }
     }
 
@@ -165,17 +197,21 @@ impl Server {
     /// A threadpool is created, and used to handle connections.
     /// The pool size is four threads.
     ///
-    /// This method blocks forever.
+    /// This method blocks until the server is stopped through its
+    /// `shutdown_handle`.
     ///
     /// The `listen` method will also serve static files. By default, that
     /// directory is "public" in the same directory as where it's run. If you'd like to change
     /// this default, please see the `set_static_directory` method.
     ///
-    /// If someone tries a path directory traversal attack, this will return a
-    /// `404`. Please note that [this is a best effort][best effort] at the
-    /// moment.
+    /// Files are served for `GET` and `HEAD` requests with a content type
+    /// based on their extension, and with `ETag` and `Last-Modified` headers
+    /// so clients can make conditional requests. A directory is served
+    /// through its `index.html`.
     ///
-    /// [best effort]: https://github.com/steveklabnik/simple-server/issues/54
+    /// If someone tries a path directory traversal attack, this will return a
+    /// `404`. The decoded path of the request is checked, and the file must
+    /// be inside the static directory after resolving symbolic links.
     ///
     /// # Panics
     ///
@@ -207,7 +243,7 @@ impl Server {
     ///     server.listen("127.0.0.1", "7979");
     /// }
     /// ```
-    pub fn listen(&self, host: &str, port: &str) -> ! {
+    pub fn listen(&self, host: &str, port: &str) {
         let listener =
             TcpListener::bind(format!("{}:{}", host, port)).expect("Error starting the server.");
 
@@ -221,7 +257,8 @@ impl Server {
     /// A threadpool is created, and used to handle connections.
     /// The pool size is four threads.
     ///
-    /// This method blocks forever.
+    /// This method blocks until the server is stopped through its
+    /// `shutdown_handle`.
     ///
     /// This method will also serve static files out of a `public` directory
     /// in the same directory as where it's run. If someone tries a path
@@ -246,30 +283,85 @@ impl Server {
     ///     server.listen_on_socket(listener);
     /// }
     /// ```
-    pub fn listen_on_socket(&self, listener: TcpListener) -> ! {
+    pub fn listen_on_socket(&self, listener: TcpListener) {
         const READ_TIMEOUT_MS: u64 = 20;
+        // backoff after failed accepts, e.g. while out of file descriptors
+        const ACCEPT_BACKOFF_MIN_MS: u64 = 10;
+        const ACCEPT_BACKOFF_MAX_MS: u64 = 1000;
         let num_threads = self.pool_size();
         let mut pool = Pool::new(num_threads);
-        let mut incoming = listener.incoming();
+
+        let accepting = match listener.local_addr() {
+            Ok(addr) => self.shutdown.bound(addr),
+            Err(_) => true,
+        };
 
         pool.scoped(|scope| {
-            loop {
-                // Incoming is an endless iterator, so it's okay to unwrap on it.
-                let stream = incoming.next().unwrap();
-                let stream = stream.expect("Error handling TCP stream.");
+            let mut backoff_ms = ACCEPT_BACKOFF_MIN_MS;
+
+            // checked before every accept, shutdown connects to wake it up
+            while accepting && !self.shutdown.is_shutdown() {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Error accepting connection, retrying in {}ms: {}", backoff_ms, e);
+                        thread::sleep(Duration::from_millis(backoff_ms));
+                        backoff_ms = (backoff_ms * 2).min(ACCEPT_BACKOFF_MAX_MS);
+                        continue;
+                    }
+                };
+                backoff_ms = ACCEPT_BACKOFF_MIN_MS;
 
                 stream
                     .set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT_MS)))
                     .expect("FATAL: Couldn't set read timeout on socket");
 
-                scope.execute(|| {
-                    self.handle_connection(stream)
+                let connection = self.shutdown.register(&stream);
+                scope.execute(move || {
+                    self.handle_connection(stream, connection)
                         .expect("Error handling connection.");
                 });
             }
+
+            info!("Server shutting down");
+            self.shutdown.drain(self.shutdown_timeout);
         })
     }
 
+    /// Returns a handle to stop the server from another thread.
+    ///
+    /// See `Shutdown` for an example.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Returns the request counts and latencies of this server.
+    ///
+    /// See `Stats` for an example.
+    pub fn stats(&self) -> Stats {
+        self.stats.clone()
+    }
+
+    /// Sets the function naming the route of a request in `Stats`.
+    ///
+    /// By default, requests are counted by their path. Paths are chosen by
+    /// clients, so a server exposing its stats should map them to a fixed
+    /// set of routes.
+    pub fn set_route_label<F>(&mut self, label: F)
+    where
+        F: Fn(&Request<Vec<u8>>) -> String + 'static + Send + Sync,
+    {
+        self.route_label = Some(Box::new(label));
+    }
+
+    /// Sets how long responses in progress may take to finish after a
+    /// shutdown, before their connections are closed.
+    ///
+    /// The default is five seconds.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
     /// Sets the proper directory for serving static files.
     ///
     /// By default, the server will serve static files inside a `public`
@@ -326,19 +418,52 @@ impl Server {
         self.static_directory = None;
     }
 
-    // Try and fetch the environment variable SIMPLESERVER_THREADS and parse it as a u32.
-    // If this fails we fall back to using the num_cpus crate.
-    fn pool_size(&self) -> u32 {
+    /// Sets the number of threads handling connections.
+    ///
+    /// By default, the pool size is taken from the `SIMPLESERVER_THREADS`
+    /// environment variable, or the number of logical cores if it is not set.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// extern crate simple_server;
+    ///
+    /// use simple_server::Server;
+    ///
+    /// fn main() {
+    ///     let mut server = Server::new(|request, mut response| {
+    ///         Ok(response.body("Hello, world!".as_bytes().to_vec())?)
+    ///     });
+    ///
+    ///     server.set_pool_size(8);
+    ///
+    ///     server.listen("127.0.0.1", "7979");
+    /// }
+    /// ```
+    pub fn set_pool_size(&mut self, threads: u32) {
+        self.pool_size = Some(threads.max(1));
+    }
+
+    /// Number of threads handling connections.
+    ///
+    /// The pool size set explicitly, else the environment variable
+    /// `SIMPLESERVER_THREADS` parsed as a u32. If this fails we fall back to
+    /// using the num_cpus crate.
+    pub fn pool_size(&self) -> u32 {
         const NUM_THREADS: &str = "SIMPLESERVER_THREADS";
         let logical_cores = num_cpus::get() as u32;
 
+        if let Some(threads) = self.pool_size {
+            return threads;
+        }
+
         match env::var(NUM_THREADS) {
             Ok(v) => v.parse::<u32>().unwrap_or(logical_cores),
             Err(_) => logical_cores,
         }
     }
 
-    fn handle_connection(&self, mut stream: TcpStream) -> Result<(), Error> {
+    fn handle_connection(&self, mut stream: TcpStream, mut connection: shutdown::Connection) -> Result<(), Error> {
         let request = match request::read(&mut stream, self.timeout) {
             Err(Error::ConnectionClosed) | Err(Error::Timeout) | Err(Error::HttpParse(_)) => {
                 return Ok(())
@@ -361,51 +486,57 @@ impl Server {
             Ok(r) => r,
         };
 
-        let mut response_builder = Response::builder();
+        let started = Instant::now();
+        let response_builder = Response::builder();
 
         // first, we serve static files
         if let Some(ref static_directory) = self.static_directory {
-            let fs_path = request.uri().to_string();
-
-            // the uri always includes a leading /, which means that join will over-write the static directory...
-            let fs_path = PathBuf::from(&fs_path[1..]);
-
-            // ... you trying to do something bad?
-            let traversal_attempt = fs_path.components().any(|component| match component {
-                std::path::Component::Normal(_) => false,
-                _ => true,
-            });
-
-            if traversal_attempt {
-                // GET OUT
-                response_builder.status(StatusCode::NOT_FOUND);
-
-                let response = response_builder
-                    .body("<h1>404</h1><p>Not found!<p>".as_bytes())
-                    .unwrap();
-
-                write_response(response, stream)?;
-                return Ok(());
-            }
-
-            let fs_path = static_directory.join(fs_path);
-
-            if Path::new(&fs_path).is_file() {
-                let mut f = File::open(&fs_path)?;
-
-                let mut source = Vec::new();
-
-                f.read_to_end(&mut source)?;
-
-                let response = response_builder.body(source)?;
-
+            if let Some(response) = static_files::serve(static_directory, &request)? {
+                let status = response.status();
                 write_response(response, stream)?;
+                self.stats.record("static", status, started.elapsed());
                 return Ok(());
             }
         }
 
+        let route = match self.route_label {
+            Some(ref label) => label(&request),
+            None => request.uri().path().to_string(),
+        };
+
         match (self.handler)(request, response_builder) {
-            Ok(response) => Ok(write_response(response, stream)?),
+            Ok(response) => {
+                let (mut parts, body) = response.into_parts();
+
+                match parts.extensions.remove::<stream::Takeover>() {
+                    Some(takeover) => {
+                        write_head(&parts, None, &mut stream)?;
+                        stream.flush()?;
+                        stream.set_read_timeout(None)?;
+                        self.stats.record(&route, parts.status, started.elapsed());
+
+                        connection.detach();
+                        thread::spawn(move || {
+                            let _connection = connection;
+                            if let Err(e) = takeover.run(&mut stream) {
+                                debug!("Streamed response ended: {}", e);
+                            }
+                        });
+                        Ok(())
+                    }
+                    None => {
+                        let status = parts.status;
+                        match parts.extensions.remove::<shared::Shared>() {
+                            Some(shared::Shared(shared)) => {
+                                write_response(Response::from_parts(parts, shared), stream)?
+                            }
+                            None => write_response(Response::from_parts(parts, body), stream)?,
+                        }
+                        self.stats.record(&route, status, started.elapsed());
+                        Ok(())
+                    }
+                }
+            }
             Err(_) => {
                 let mut response_builder = Response::builder();
                 response_builder.status(StatusCode::INTERNAL_SERVER_ERROR);
@@ -414,7 +545,9 @@ impl Server {
                     .body("<h1>500</h1><p>Internal Server Error!<p>".as_bytes())
                     .unwrap();
 
-                Ok(write_response(response, stream)?)
+                write_response(response, stream)?;
+                self.stats.record(&route, StatusCode::INTERNAL_SERVER_ERROR, started.elapsed());
+                Ok(())
             }
         }
     }
@@ -424,11 +557,19 @@ fn write_response<T: Borrow<[u8]>, S: Write>(
     response: Response<T>,
     mut stream: S,
 ) -> Result<(), Error> {
-    use fmt::Write;
-
     let (parts, body) = response.into_parts();
     let body: &[u8] = body.borrow();
 
+    write_head(&parts, Some(body.len()), &mut stream)?;
+    stream.write(body)?;
+    Ok(stream.flush()?)
+}
+
+// Writes status line and headers, a `content-length` is only added if the
+// length of the body is known up front.
+fn write_head<S: Write>(parts: &Parts, body_len: Option<usize>, stream: &mut S) -> Result<(), Error> {
+    use fmt::Write;
+
     let mut text = format!(
         "HTTP/1.1 {} {}\r\n",
         parts.status.as_str(),
@@ -445,8 +586,10 @@ fn write_response<T: Borrow<[u8]>, S: Write>(
     if !parts.headers.contains_key(http::header::CONNECTION) {
         write!(text, "connection: close\r\n").unwrap();
     }
-    if !parts.headers.contains_key(http::header::CONTENT_LENGTH) {
-        write!(text, "content-length: {}\r\n", body.len()).unwrap();
+    if let Some(body_len) = body_len {
+        if !parts.headers.contains_key(http::header::CONTENT_LENGTH) {
+            write!(text, "content-length: {}\r\n", body_len).unwrap();
+        }
     }
     for (k, v) in parts.headers.iter() {
         write!(text, "{}: {}\r\n", k.as_str(), v.to_str().unwrap()).unwrap();
@@ -455,8 +598,7 @@ fn write_response<T: Borrow<[u8]>, S: Write>(
     write!(text, "\r\n").unwrap();
 
     stream.write(text.as_bytes())?;
-    stream.write(body)?;
-    Ok(stream.flush()?)
+    Ok(())
 }
 
 #[test]
@@ -496,3 +638,21 @@ fn test_write_response_no_headers() {
         Hello rust";
     assert_eq!(&expected[..], &output[..]);
 }
+
+#[test]
+fn test_write_head_without_length() {
+    let mut builder = http::response::Builder::new();
+    builder.header(http::header::DATE, "Thu, 01 Jan 1970 00:00:00 GMT");
+    builder.header(http::header::CONTENT_TYPE, "text/event-stream".as_bytes());
+    builder.status(http::StatusCode::OK);
+
+    let (parts, _) = builder.body(()).unwrap().into_parts();
+    let mut output = vec![];
+    write_head(&parts, None, &mut output).unwrap();
+    let expected = b"HTTP/1.1 200 OK\r\n\
+        connection: close\r\n\
+        date: Thu, 01 Jan 1970 00:00:00 GMT\r\n\
+        content-type: text/event-stream\r\n\
+        \r\n";
+    assert_eq!(&expected[..], &output[..]);
+}
@@ -0,0 +1,33 @@
+use super::{ResponseBuilder, ResponseResult};
+use std::sync::Arc;
+
+/// Marker placed in the response extensions of a response with a shared body.
+pub(crate) struct Shared(pub(crate) Arc<[u8]>);
+
+/// Responds with a body which is shared with other responses.
+///
+/// The server writes `body` straight from the `Arc`, so content served to
+/// many clients, like a cached document, is not copied for every response.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate simple_server;
+///
+/// use std::sync::Arc;
+/// use simple_server::Server;
+///
+/// fn main() {
+///     let hello: Arc<[u8]> = Arc::from(&b"Hello, world!"[..]);
+///
+///     let server = Server::new(move |request, mut response| {
+///         simple_server::shared(&mut response, hello.clone())
+///     });
+///
+///     server.listen("127.0.0.1", "7979");
+/// }
+/// ```
+pub fn shared(response: &mut ResponseBuilder, body: Arc<[u8]>) -> ResponseResult {
+    response.extension(Shared(body));
+    Ok(response.body(Vec::new())?)
+}
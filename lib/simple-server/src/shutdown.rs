@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown as Both, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// A handle to stop a server from another thread.
+///
+/// Once `shutdown` is called, the server stops accepting connections and
+/// waits for the responses in progress, up to its shutdown timeout. The
+/// connections still open after that (including streamed responses) are
+/// closed, and `listen` returns.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate simple_server;
+///
+/// use std::thread;
+/// use simple_server::Server;
+///
+/// fn main() {
+///     let server = Server::new(|request, mut response| {
+///         Ok(response.body("Hello, world!".as_bytes().to_vec())?)
+///     });
+///
+///     let shutdown = server.shutdown_handle();
+///     thread::spawn(move || shutdown.shutdown());
+///
+///     server.listen("127.0.0.1", "7979");
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    state: Arc<State>,
+}
+
+#[derive(Default)]
+struct State {
+    requested: AtomicBool,
+    local_addr: Mutex<Option<SocketAddr>>,
+    next_id: AtomicUsize,
+    connections: Mutex<HashMap<usize, TcpStream>>,
+    // connections handled by the threadpool, streamed responses don't count
+    busy: Mutex<usize>,
+    idle: Condvar,
+}
+
+impl Shutdown {
+    /// Stops accepting connections.
+    pub fn shutdown(&self) {
+        // under the lock of the address, so `bound` either sees the request
+        // or the address is there to connect to
+        let local_addr = self.state.local_addr.lock().unwrap();
+        if self.state.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let local_addr = *local_addr;
+
+        // wake the listener up, it is blocked in accept
+        if let Some(mut addr) = local_addr {
+            if addr.ip().is_unspecified() {
+                match addr {
+                    SocketAddr::V4(_) => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+                    SocketAddr::V6(_) => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+                }
+            }
+            let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+        }
+    }
+
+    /// Whether `shutdown` has been called.
+    pub fn is_shutdown(&self) -> bool {
+        self.state.requested.load(Ordering::SeqCst)
+    }
+
+    // Returns false if shutdown was requested before, nobody wakes the
+    // listener up then.
+    pub(crate) fn bound(&self, addr: SocketAddr) -> bool {
+        let mut local_addr = self.state.local_addr.lock().unwrap();
+        *local_addr = Some(addr);
+        !self.state.requested.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn register(&self, stream: &TcpStream) -> Connection {
+        let id = self.state.next_id.fetch_add(1, Ordering::SeqCst);
+        if let Ok(stream) = stream.try_clone() {
+            self.state.connections.lock().unwrap().insert(id, stream);
+        }
+        *self.state.busy.lock().unwrap() += 1;
+
+        Connection {
+            id,
+            state: self.state.clone(),
+            busy: true,
+        }
+    }
+
+    // Waits until the threadpool is idle or the timeout passed, then closes
+    // every connection which is still open.
+    pub(crate) fn drain(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut busy = self.state.busy.lock().unwrap();
+
+        while *busy > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("Closing {} connections still in progress", *busy);
+                break;
+            }
+            busy = self.state.idle.wait_timeout(busy, deadline - now).unwrap().0;
+        }
+        drop(busy);
+
+        for (_, stream) in self.state.connections.lock().unwrap().drain() {
+            let _ = stream.shutdown(Both::Both);
+        }
+    }
+}
+
+/// Bookkeeping for an accepted connection, until it is dropped.
+pub(crate) struct Connection {
+    id: usize,
+    state: Arc<State>,
+    busy: bool,
+}
+
+impl Connection {
+    // The connection is handed over to a streamed response, the threadpool is
+    // done with it.
+    pub(crate) fn detach(&mut self) {
+        if self.busy {
+            self.busy = false;
+            self.state.done();
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.state.connections.lock().unwrap().remove(&self.id);
+        self.detach();
+    }
+}
+
+impl State {
+    fn done(&self) {
+        let mut busy = self.busy.lock().unwrap();
+        *busy -= 1;
+        if *busy == 0 {
+            self.idle.notify_all();
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use super::{Method, Request, Response, StatusCode};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use error::Error;
+
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Content type by file extension, everything else is served as binary.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Decodes `%XX` escapes, `None` for broken escapes or if the result is not UTF-8.
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            let hex = ::std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Maps the path of a request URI to a relative file system path.
+///
+/// Returns `None` if the path tries to leave the static directory: parent
+/// or root components, drive prefixes, backslashes or NUL bytes, also when
+/// they are percent-encoded.
+fn relative_path(uri_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(uri_path)?;
+
+    if decoded.contains('\\') || decoded.contains('\0') {
+        return None;
+    }
+
+    // the path always has a leading /, which means that join would over-write the static directory...
+    let relative = PathBuf::from(decoded.trim_start_matches('/'));
+
+    // ... you trying to do something bad?
+    let traversal_attempt = relative.components().any(|c| !matches!(c, Component::Normal(_)));
+
+    if traversal_attempt {
+        None
+    } else {
+        Some(relative)
+    }
+}
+
+fn not_found() -> Response<Vec<u8>> {
+    let mut response = Response::builder();
+    response.status(StatusCode::NOT_FOUND);
+    response
+        .body("<h1>404</h1><p>Not found!<p>".as_bytes().to_vec())
+        .unwrap()
+}
+
+/// Returns true if a conditional request can be answered with `304 Not Modified`.
+///
+/// `If-None-Match` takes precedence over `If-Modified-Since`, like RFC 7232 says.
+fn not_modified(request: &Request<Vec<u8>>, etag: &str, modified: i64) -> bool {
+    let header = |name| request.headers().get(name).and_then(|v| v.to_str().ok());
+
+    if let Some(tags) = header("if-none-match") {
+        return tags
+            .split(',')
+            .map(|t| t.trim())
+            .any(|t| t == "*" || t.trim_start_matches("W/") == etag.trim_start_matches("W/"));
+    }
+
+    match header("if-modified-since").and_then(|since| time::strptime(since, HTTP_DATE).ok()) {
+        Some(since) => modified <= since.to_timespec().sec,
+        None => false,
+    }
+}
+
+/// Serves a file from `directory` for a GET or HEAD request.
+///
+/// Returns `None` if there is no such file, so the request goes on to the
+/// handler. Directories are served through their `index.html`, and a
+/// `404` is returned right away if the path tries to leave `directory`.
+pub fn serve(directory: &Path, request: &Request<Vec<u8>>) -> Result<Option<Response<Vec<u8>>>, Error> {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return Ok(None);
+    }
+
+    let relative = match relative_path(request.uri().path()) {
+        Some(relative) => relative,
+        None => return Ok(Some(not_found())),
+    };
+
+    let mut fs_path = directory.join(relative);
+
+    if fs_path.is_dir() {
+        // relative links in the index only work with a trailing slash
+        if !request.uri().path().ends_with('/') {
+            if !fs_path.join("index.html").is_file() {
+                return Ok(None);
+            }
+
+            let mut location = format!("{}/", request.uri().path());
+            if let Some(query) = request.uri().query() {
+                location = format!("{}?{}", location, query);
+            }
+
+            let mut response = Response::builder();
+            response.status(StatusCode::MOVED_PERMANENTLY);
+            response.header("location", location.as_str());
+            return Ok(Some(response.body(Vec::new())?));
+        }
+        fs_path = fs_path.join("index.html");
+    }
+
+    if !fs_path.is_file() {
+        return Ok(None);
+    }
+
+    // symlinks must not lead out of the static directory either
+    match (fs_path.canonicalize(), directory.canonicalize()) {
+        (Ok(ref file), Ok(ref directory)) if file.starts_with(directory) => (),
+        _ => return Ok(Some(not_found())),
+    }
+
+    let metadata = fs::metadata(&fs_path)?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    let etag = format!("W/\"{:x}-{:x}\"", metadata.len(), modified.as_nanos());
+    let last_modified = time::strftime(
+        HTTP_DATE,
+        &time::at_utc(time::Timespec::new(modified.as_secs() as i64, 0)),
+    )
+    .unwrap();
+
+    let mut response = Response::builder();
+    response.header("etag", etag.as_str());
+    response.header("last-modified", last_modified.as_str());
+
+    if not_modified(request, &etag, modified.as_secs() as i64) {
+        response.status(StatusCode::NOT_MODIFIED);
+        response.header("content-length", "0");
+        return Ok(Some(response.body(Vec::new())?));
+    }
+
+    response.header("content-type", content_type(&fs_path));
+
+    if request.method() == Method::HEAD {
+        response.header("content-length", metadata.len().to_string().as_str());
+        return Ok(Some(response.body(Vec::new())?));
+    }
+
+    let mut source = Vec::new();
+    File::open(&fs_path)?.read_to_end(&mut source)?;
+
+    Ok(Some(response.body(source)?))
+}
+
+#[cfg(test)]
+mod static_files_should {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request<Vec<u8>> {
+        let mut request = Request::builder();
+        request.uri("/index.html");
+        for &(name, value) in headers {
+            request.header(name, value);
+        }
+        request.body(Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn reject_traversal_attempts() {
+        assert_eq!(Some(PathBuf::from("css/app.css")), relative_path("/css/app.css"));
+        assert_eq!(Some(PathBuf::from("my file.html")), relative_path("/my%20file.html"));
+        assert_eq!(None, relative_path("/../secret"));
+        assert_eq!(None, relative_path("/%2e%2e/secret"));
+        assert_eq!(None, relative_path("/a/%2E%2E/%2E%2E/secret"));
+        assert_eq!(None, relative_path("/..%5csecret"));
+        assert_eq!(Some(PathBuf::from("etc/passwd")), relative_path("//etc/passwd"));
+        assert_eq!(None, relative_path("/%00"));
+        assert_eq!(None, relative_path("/%zz"));
+    }
+
+    #[test]
+    fn pick_content_type() {
+        assert_eq!("text/html; charset=utf-8", content_type(Path::new("index.HTML")));
+        assert_eq!("text/javascript; charset=utf-8", content_type(Path::new("app.js")));
+        assert_eq!("application/octet-stream", content_type(Path::new("README")));
+    }
+
+    #[test]
+    fn answer_conditional_requests() {
+        let etag = "W/\"a-b\"";
+        // Thu, 01 Jan 1970 00:16:40 GMT
+        let modified = 1000;
+
+        assert!(!not_modified(&request(&[]), etag, modified));
+        assert!(not_modified(&request(&[("if-none-match", "\"x\", W/\"a-b\"")]), etag, modified));
+        assert!(not_modified(&request(&[("if-none-match", "\"a-b\"")]), etag, modified));
+        assert!(!not_modified(&request(&[("if-none-match", "\"x\"")]), etag, modified));
+        assert!(not_modified(
+            &request(&[("if-modified-since", "Thu, 01 Jan 1970 00:16:40 GMT")]),
+            etag,
+            modified
+        ));
+        assert!(!not_modified(
+            &request(&[("if-modified-since", "Thu, 01 Jan 1970 00:16:39 GMT")]),
+            etag,
+            modified
+        ));
+    }
+}
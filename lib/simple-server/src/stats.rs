@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use http::StatusCode;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+pub const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Requests handled for one route.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouteStats {
+    /// Number of requests by status code.
+    pub requests: BTreeMap<u16, u64>,
+    /// Number of requests by latency, one entry per bucket in
+    /// `LATENCY_BUCKETS` plus one for slower requests. Not cumulative.
+    pub latencies: Vec<u64>,
+    /// Time spent on all requests, from reading the request until the
+    /// response is written. For streamed responses until the head is written.
+    pub latency_sum: Duration,
+}
+
+impl RouteStats {
+    /// Total number of requests.
+    pub fn count(&self) -> u64 {
+        self.requests.values().sum()
+    }
+}
+
+/// Request counts and latencies per route, shared with the server.
+///
+/// Routes are named by the function passed to `Server::set_route_label`,
+/// static files are counted as `static`.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate simple_server;
+///
+/// use simple_server::Server;
+///
+/// fn main() {
+///     let mut server = Server::new(|request, mut response| {
+///         Ok(response.body("Hello, world!".as_bytes().to_vec())?)
+///     });
+///     server.set_route_label(|request| String::from(request.uri().path()));
+///     let stats = server.stats();
+///
+///     std::thread::spawn(move || server.listen("127.0.0.1", "7979"));
+///
+///     for (route, route_stats) in stats.snapshot() {
+///         println!("{}: {} requests", route, route_stats.count());
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    routes: Arc<Mutex<BTreeMap<String, RouteStats>>>,
+}
+
+impl Stats {
+    /// Count a request.
+    pub fn record(&self, route: &str, status: StatusCode, latency: Duration) {
+        let mut routes = self.routes.lock().unwrap();
+        let stats = routes.entry(route.to_string()).or_insert_with(|| RouteStats {
+            latencies: vec![0; LATENCY_BUCKETS.len() + 1],
+            ..RouteStats::default()
+        });
+
+        *stats.requests.entry(status.as_u16()).or_insert(0) += 1;
+
+        let secs = latency.as_secs() as f64 + f64::from(latency.subsec_nanos()) / 1e9;
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        stats.latencies[bucket] += 1;
+        stats.latency_sum += latency;
+    }
+
+    /// The requests counted so far, by route.
+    pub fn snapshot(&self) -> BTreeMap<String, RouteStats> {
+        self.routes.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,61 @@
+use super::{ResponseBuilder, ResponseResult};
+use std::io;
+use std::net::TcpStream;
+use std::sync::Mutex;
+
+type Body = Box<dyn FnOnce(&mut TcpStream) -> io::Result<()> + Send>;
+
+/// Marker placed in the response extensions of a streamed response.
+///
+/// The `Mutex` is only there because extensions must be `Sync`, the body
+/// is taken out exactly once by the server.
+pub(crate) struct Takeover(Mutex<Option<Body>>);
+
+impl Takeover {
+    pub(crate) fn run(self, stream: &mut TcpStream) -> io::Result<()> {
+        let body = self.0.into_inner().ok().and_then(|body| body);
+
+        match body {
+            Some(body) => body(stream),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Turns a response into a streamed, long-lived response.
+///
+/// Instead of writing the (empty) body and closing the connection, the
+/// server writes the status line and headers and then hands the connection
+/// to `body`. No `content-length` is sent, the response ends when `body`
+/// returns and the connection is closed. This is what you want for things
+/// like server-sent events.
+///
+/// The body runs on its own thread, so a long-lived stream does not take
+/// one of the threads in the threadpool away from other requests.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate simple_server;
+///
+/// use std::io::Write;
+/// use simple_server::Server;
+///
+/// fn main() {
+///     let server = Server::new(|request, mut response| {
+///         response.header("content-type", "text/event-stream");
+///         simple_server::stream(&mut response, |stream| {
+///             stream.write_all(b"data: Hello, world!\n\n")
+///         })
+///     });
+///
+///     server.listen("127.0.0.1", "7979");
+/// }
+/// ```
+pub fn stream<F>(response: &mut ResponseBuilder, body: F) -> ResponseResult
+where
+    F: FnOnce(&mut TcpStream) -> io::Result<()> + Send + 'static,
+{
+    response.extension(Takeover(Mutex::new(Some(Box::new(body)))));
+    Ok(response.body(Vec::new())?)
+}
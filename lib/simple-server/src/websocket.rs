@@ -0,0 +1,386 @@
+//! WebSocket support (RFC 6455).
+//!
+//! Use `upgrade` from a handler to accept the opening handshake, the
+//! connection is then handed to your closure as a `WebSocket`.
+
+use super::{Request, ResponseBuilder, ResponseResult, StatusCode};
+use base64::Engine;
+use sha1_smol::Sha1;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message we accept from a client.
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// A WebSocket message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// Close frame with optional status code.
+    Close(Option<u16>),
+}
+
+/// The sending half of a WebSocket, can be cloned and used from other threads.
+#[derive(Clone)]
+pub struct Sender {
+    stream: Arc<Mutex<TcpStream>>,
+}
+
+impl Sender {
+    /// Sends a message as a single frame.
+    pub fn send(&self, message: &Message) -> io::Result<()> {
+        let (opcode, payload) = match *message {
+            Message::Text(ref text) => (OP_TEXT, text.as_bytes().to_vec()),
+            Message::Binary(ref data) => (OP_BINARY, data.clone()),
+            Message::Ping(ref data) => (OP_PING, data.clone()),
+            Message::Pong(ref data) => (OP_PONG, data.clone()),
+            Message::Close(Some(code)) => (OP_CLOSE, code.to_be_bytes().to_vec()),
+            Message::Close(None) => (OP_CLOSE, Vec::new()),
+        };
+
+        let mut stream = self.stream.lock().unwrap();
+        stream.write_all(&encode_frame(opcode, &payload))?;
+        stream.flush()
+    }
+
+    /// Sends a close frame and shuts the connection down.
+    pub fn close(&self, code: u16) -> io::Result<()> {
+        let result = self.send(&Message::Close(Some(code)));
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+        result
+    }
+}
+
+/// A server side WebSocket connection.
+pub struct WebSocket {
+    stream: TcpStream,
+    sender: Sender,
+}
+
+impl WebSocket {
+    fn new(stream: TcpStream) -> io::Result<WebSocket> {
+        let writer = stream.try_clone()?;
+
+        Ok(WebSocket {
+            stream,
+            sender: Sender {
+                stream: Arc::new(Mutex::new(writer)),
+            },
+        })
+    }
+
+    /// A handle to send messages, e.g. from another thread.
+    pub fn sender(&self) -> Sender {
+        self.sender.clone()
+    }
+
+    /// Sends a message.
+    pub fn send(&self, message: &Message) -> io::Result<()> {
+        self.sender.send(message)
+    }
+
+    /// Blocks until the next message arrives.
+    ///
+    /// Pings are answered with a pong before they are returned, and a close
+    /// frame from the client is answered with a close frame. Fragmented
+    /// messages are returned once they are complete, pings and pongs between
+    /// their fragments are answered or dropped without returning.
+    pub fn read(&mut self) -> io::Result<Message> {
+        let mut message: Option<(u8, Vec<u8>)> = None;
+
+        loop {
+            let (fin, opcode, payload) = read_frame(&mut self.stream)?;
+
+            match opcode {
+                OP_PING => {
+                    self.send(&Message::Pong(payload.clone()))?;
+                    if message.is_none() {
+                        return Ok(Message::Ping(payload));
+                    }
+                    continue;
+                }
+                OP_PONG if message.is_none() => return Ok(Message::Pong(payload)),
+                OP_PONG => continue,
+                OP_CLOSE => {
+                    let code = if payload.len() >= 2 {
+                        Some(u16::from_be_bytes([payload[0], payload[1]]))
+                    } else {
+                        None
+                    };
+                    let _ = self.sender.close(code.unwrap_or(1000));
+                    return Ok(Message::Close(code));
+                }
+                OP_TEXT | OP_BINARY if message.is_none() => message = Some((opcode, payload)),
+                OP_CONTINUATION if message.is_some() => {
+                    let (_, ref mut data) = *message.as_mut().unwrap();
+                    if data.len() + payload.len() > MAX_MESSAGE_SIZE {
+                        return Err(invalid_data("message too large"));
+                    }
+                    data.extend_from_slice(&payload);
+                }
+                _ => return Err(invalid_data("unexpected opcode")),
+            }
+
+            if fin {
+                let (opcode, data) = message.take().unwrap();
+
+                return match opcode {
+                    OP_TEXT => String::from_utf8(data)
+                        .map(Message::Text)
+                        .map_err(|_| invalid_data("text message is not UTF-8")),
+                    _ => Ok(Message::Binary(data)),
+                };
+            }
+        }
+    }
+}
+
+/// Returns true if the request asks for a WebSocket upgrade.
+pub fn is_upgrade(request: &Request<Vec<u8>>) -> bool {
+    let header_contains = |name: &str, token: &str| {
+        request
+            .headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|v| v.trim().eq_ignore_ascii_case(token))
+    };
+
+    header_contains("upgrade", "websocket")
+        && header_contains("connection", "upgrade")
+        && header_contains("sec-websocket-version", "13")
+        && request.headers().contains_key("sec-websocket-key")
+}
+
+/// Accepts a WebSocket handshake and hands the connection to `handler`.
+///
+/// If the request is not a valid WebSocket upgrade request, a
+/// `400 Bad Request` is returned instead. Like `stream`, the handler runs
+/// on its own thread.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate simple_server;
+///
+/// use simple_server::Server;
+/// use simple_server::websocket::{self, Message};
+///
+/// fn main() {
+///     let server = Server::new(|request, mut response| {
+///         websocket::upgrade(&request, &mut response, |mut ws| loop {
+///             match ws.read()? {
+///                 Message::Text(text) => ws.send(&Message::Text(text))?,
+///                 Message::Close(_) => return Ok(()),
+///                 _ => (),
+///             }
+///         })
+///     });
+///
+///     server.listen("127.0.0.1", "7979");
+/// }
+/// ```
+pub fn upgrade<F>(request: &Request<Vec<u8>>, response: &mut ResponseBuilder, handler: F) -> ResponseResult
+where
+    F: FnOnce(WebSocket) -> io::Result<()> + Send + 'static,
+{
+    if !is_upgrade(request) {
+        response.status(StatusCode::BAD_REQUEST);
+        return Ok(response.body("<h1>400</h1><p>WebSocket upgrade expected!<p>".as_bytes().to_vec())?);
+    }
+
+    let key = request.headers()["sec-websocket-key"].as_bytes();
+
+    response.status(StatusCode::SWITCHING_PROTOCOLS);
+    response.header("upgrade", "websocket");
+    response.header("connection", "Upgrade");
+    response.header("sec-websocket-accept", accept_key(key).as_str());
+
+    super::stream(response, move |stream| {
+        let ws = WebSocket::new(stream.try_clone()?)?;
+        handler(ws)
+    })
+}
+
+fn accept_key(key: &[u8]) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key);
+    sha1.update(GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(sha1.digest().bytes())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else if payload.len() <= 0xFFFF {
+        frame.push(126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// Reads one frame, returns the FIN flag, the opcode and the unmasked payload.
+fn read_frame<S: Read>(stream: &mut S) -> io::Result<(bool, u8, Vec<u8>)> {
+    let mut head = [0_u8; 2];
+    stream.read_exact(&mut head)?;
+
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    let masked = head[1] & 0x80 != 0;
+
+    // no extensions are negotiated, so the reserved bits must be clear
+    if head[0] & 0x70 != 0 {
+        return Err(invalid_data("reserved bits set"));
+    }
+
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut len = [0_u8; 2];
+            stream.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0_u8; 8];
+            stream.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+
+    if !masked {
+        return Err(invalid_data("client frames must be masked"));
+    }
+    if opcode & 0x08 != 0 && (!fin || len > 125) {
+        return Err(invalid_data("control frames must not be fragmented or longer than 125 bytes"));
+    }
+    if len > MAX_MESSAGE_SIZE as u64 {
+        return Err(invalid_data("message too large"));
+    }
+
+    let mut mask = [0_u8; 4];
+    stream.read_exact(&mut mask)?;
+
+    let mut payload = vec![0_u8; len as usize];
+    stream.read_exact(&mut payload)?;
+
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+
+    Ok((fin, opcode, payload))
+}
+
+#[cfg(test)]
+mod websocket_should {
+    use super::*;
+
+    #[test]
+    fn compute_accept_key() {
+        // example from RFC 6455, section 1.3
+        assert_eq!(
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+            accept_key(b"dGhlIHNhbXBsZSBub25jZQ==")
+        );
+    }
+
+    #[test]
+    fn read_masked_frame() {
+        // "Hello" from RFC 6455, section 5.7
+        let frame = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (fin, opcode, payload) = read_frame(&mut &frame[..]).unwrap();
+        assert!(fin);
+        assert_eq!(OP_TEXT, opcode);
+        assert_eq!(b"Hello", &payload[..]);
+    }
+
+    #[test]
+    fn reject_unmasked_frame() {
+        let frame = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        assert!(read_frame(&mut &frame[..]).is_err());
+    }
+
+    #[test]
+    fn reject_reserved_bits_and_invalid_control_frames() {
+        // RSV1 set on a text frame
+        let frame = [0xC1, 0x80, 0, 0, 0, 0];
+        assert!(read_frame(&mut &frame[..]).is_err());
+
+        // ping without FIN
+        let frame = [OP_PING, 0x80, 0, 0, 0, 0];
+        assert!(read_frame(&mut &frame[..]).is_err());
+
+        // close with a 126 byte payload
+        let mut frame = vec![0x80 | OP_CLOSE, 0x80 | 126, 0, 126, 0, 0, 0, 0];
+        frame.extend_from_slice(&[0; 126]);
+        assert!(read_frame(&mut &frame[..]).is_err());
+
+        let mut frame = vec![0x80 | OP_PING, 0x80 | 125, 0, 0, 0, 0];
+        frame.extend_from_slice(&[0; 125]);
+        assert!(read_frame(&mut &frame[..]).is_ok());
+    }
+
+    #[test]
+    fn answer_ping_between_fragments() {
+        use std::net::TcpListener;
+
+        fn masked(first: u8, payload: &[u8]) -> Vec<u8> {
+            let mask = [1, 2, 3, 4];
+            let mut frame = vec![first, 0x80 | payload.len() as u8];
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            frame
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut socket = WebSocket::new(listener.accept().unwrap().0).unwrap();
+
+        client.write_all(&masked(OP_TEXT, b"Hel")).unwrap();
+        client.write_all(&masked(0x80 | OP_PING, b"hi")).unwrap();
+        client.write_all(&masked(0x80 | OP_CONTINUATION, b"lo")).unwrap();
+
+        assert_eq!(Message::Text(String::from("Hello")), socket.read().unwrap());
+
+        let mut pong = [0_u8; 4];
+        client.read_exact(&mut pong).unwrap();
+        assert_eq!([0x80 | OP_PONG, 2, b'h', b'i'], pong);
+    }
+
+    #[test]
+    fn encode_frame_lengths() {
+        assert_eq!(vec![0x81, 0x02, b'h', b'i'], encode_frame(OP_TEXT, b"hi"));
+        assert_eq!(&[0x82, 126, 0x01, 0x00], &encode_frame(OP_BINARY, &[0; 256])[..4]);
+        assert_eq!(
+            &[0x82, 127, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00],
+            &encode_frame(OP_BINARY, &[0; 65536])[..10]
+        );
+    }
+}
@@ -17,3 +17,129 @@ fn test_error_fallback() {
         Ok(response.body("".as_bytes().to_vec())?)
     });
 }
+
+#[test]
+fn test_shutdown_closes_streams() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut server = Server::new(|_request, mut response| {
+        simple_server::stream(&mut response, |stream| loop {
+            stream.write_all(b"tick\n")?;
+            thread::sleep(Duration::from_millis(10));
+        })
+    });
+    server.set_shutdown_timeout(Duration::from_millis(100));
+    let shutdown = server.shutdown_handle();
+    let server = thread::spawn(move || server.listen_on_socket(listener));
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    let mut head = [0; 15];
+    client.read_exact(&mut head).unwrap();
+    assert_eq!(b"HTTP/1.1 200 OK", &head);
+
+    shutdown.shutdown();
+    server.join().unwrap();
+
+    // the stream ends instead of ticking forever
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut rest = Vec::new();
+    assert!(client.read_to_end(&mut rest).is_ok());
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn test_shutdown_before_listen() {
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = Server::new(|_request, mut response| Ok(response.body(Vec::new())?));
+    server.shutdown_handle().shutdown();
+
+    let (done, stopped) = mpsc::channel();
+    thread::spawn(move || {
+        server.listen_on_socket(listener);
+        done.send(()).unwrap();
+    });
+
+    assert!(stopped.recv_timeout(Duration::from_secs(5)).is_ok());
+}
+
+#[test]
+fn test_stats_count_requests_per_route() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut server = Server::new(|request, mut response| {
+        if request.uri().path() == "/missing" {
+            response.status(simple_server::StatusCode::NOT_FOUND);
+        }
+        Ok(response.body(Vec::new())?)
+    });
+    server.dont_serve_static_files();
+    server.set_route_label(|request| String::from(&request.uri().path()[..2]));
+    let stats = server.stats();
+    let shutdown = server.shutdown_handle();
+    let server = thread::spawn(move || server.listen_on_socket(listener));
+
+    for path in &["/a", "/abc", "/missing"] {
+        let mut client = TcpStream::connect(addr).unwrap();
+        write!(client, "GET {} HTTP/1.1\r\n\r\n", path).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+    }
+
+    shutdown.shutdown();
+    server.join().unwrap();
+
+    let routes = stats.snapshot();
+    assert_eq!(2, routes["/a"].count());
+    assert_eq!(Some(&2), routes["/a"].requests.get(&200));
+    assert_eq!(Some(&1), routes["/m"].requests.get(&404));
+    assert_eq!(2, routes["/a"].latencies.iter().sum::<u64>());
+}
+
+#[test]
+fn test_shared_body() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let body: Arc<[u8]> = Arc::from(&b"[1,2,3]"[..]);
+    let served = body.clone();
+    let mut server = Server::new(move |_request, mut response| {
+        simple_server::shared(&mut response, served.clone())
+    });
+    server.dont_serve_static_files();
+    let shutdown = server.shutdown_handle();
+    let server = thread::spawn(move || server.listen_on_socket(listener));
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+
+    shutdown.shutdown();
+    server.join().unwrap();
+
+    assert!(response.contains("content-length: 7\r\n"));
+    assert!(response.ends_with("\r\n\r\n[1,2,3]"));
+    assert_eq!(1, Arc::strong_count(&body));
+}
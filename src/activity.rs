@@ -0,0 +1,332 @@
+//! The ride of the rider in focus as a training file.
+//!
+//! Focus updates are collected into one sample per second of ride time, like
+//! a bike computer would record them. A new rider in focus or the ride time
+//! going backwards starts a new activity. TPV does not broadcast coordinates,
+//! so unless the focus document carries `latitude` and `longitude`, the track
+//! is synthetic: a straight line east along the equator, as long as the
+//! distance ridden.
+
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::cache::{Cache, Subscription};
+use crate::fit;
+use crate::model::{Document, Focus};
+use crate::recorder::Record;
+
+/// Meters per degree of longitude on the equator.
+const METERS_PER_DEGREE: f64 = 111_319.49;
+
+/// Training file formats an activity can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Fit,
+    Tcx,
+    Gpx,
+}
+
+impl Format {
+    /// Format for a file extension, e.g. "fit".
+    pub fn from_extension(extension: &str) -> Option<Format> {
+        match extension {
+            "fit" => Some(Format::Fit),
+            "tcx" => Some(Format::Tcx),
+            "gpx" => Some(Format::Gpx),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Fit => "fit",
+            Format::Tcx => "tcx",
+            Format::Gpx => "gpx",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Fit => "application/vnd.ant.fit",
+            Format::Tcx => "application/vnd.garmin.tcx+xml",
+            Format::Gpx => "application/gpx+xml",
+        }
+    }
+}
+
+/// One second of the ride, distance and height in m, speed in m/s.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sample {
+    /// Ride time in s.
+    pub time: f64,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub distance: f64,
+    pub height: f64,
+    pub speed: f64,
+    pub power: f64,
+    pub cadence: f64,
+    pub heartrate: f64,
+}
+
+/// The ride of one rider, see the module documentation.
+#[derive(Debug, Clone, Default)]
+pub struct Activity {
+    pub name: String,
+    /// Wall clock time at ride time 0.
+    pub start: Option<SystemTime>,
+    pub samples: Vec<Sample>,
+}
+
+impl Activity {
+    pub fn new() -> Activity {
+        Activity::default()
+    }
+
+    /// Add a focus sample received at `received`.
+    pub fn add(&mut self, received: SystemTime, focus: &Focus) {
+        // valid JSON, but no ride time
+        let offset = match Duration::try_from_secs_f64(focus.time.max(0.0)) {
+            Ok(offset) => offset,
+            Err(_) => return,
+        };
+
+        if focus.name != self.name || self.samples.last().is_some_and(|last| focus.time < last.time) {
+            if !self.samples.is_empty() {
+                log::info!("New activity of {}", focus.name);
+            }
+            *self = Activity { name: focus.name.clone(), ..Activity::default() };
+        }
+
+        // one sample per second of ride time
+        if self.samples.last().is_some_and(|last| focus.time.floor() <= last.time.floor()) {
+            return;
+        }
+
+        let start = self.start.unwrap_or_else(|| received.checked_sub(offset).unwrap_or(received));
+        if start.checked_add(offset).is_none() {
+            return;
+        }
+        self.start = Some(start);
+
+        let coordinate = |key: &str| focus.extra.get(key).and_then(|value| value.as_f64());
+        let (latitude, longitude) = match (coordinate("latitude"), coordinate("longitude")) {
+            (Some(latitude), Some(longitude)) => (latitude, longitude),
+            _ => (0.0, focus.distance / METERS_PER_DEGREE),
+        };
+
+        self.samples.push(Sample {
+            time: focus.time,
+            latitude,
+            longitude,
+            distance: focus.distance,
+            height: focus.height,
+            speed: focus.speed / 1000.0,
+            power: focus.power,
+            cadence: focus.cadence,
+            heartrate: focus.heartrate,
+        });
+    }
+
+    /// The last activity in a journal.
+    pub fn from_records(records: &[Record]) -> Activity {
+        let mut activity = Activity::new();
+
+        for record in records.iter().filter(|record| record.name == "focus") {
+            if let Some(Ok(document)) = Document::parse(&record.name, &record.raw) {
+                if let Some(focus) = document.focus() {
+                    activity.add(UNIX_EPOCH + Duration::from_millis(record.time), focus);
+                }
+            }
+        }
+
+        activity
+    }
+
+    /// Wall clock time of a sample.
+    pub fn time_of(&self, sample: &Sample) -> SystemTime {
+        let start = self.start.unwrap_or(UNIX_EPOCH);
+        Duration::try_from_secs_f64(sample.time.max(0.0)).ok()
+            .and_then(|offset| start.checked_add(offset))
+            .unwrap_or(start)
+    }
+
+    /// Elapsed ride time in s.
+    pub fn duration(&self) -> f64 {
+        match (self.samples.first(), self.samples.last()) {
+            (Some(first), Some(last)) => last.time - first.time,
+            _ => 0.0,
+        }
+    }
+
+    /// Distance covered in m.
+    pub fn distance(&self) -> f64 {
+        match (self.samples.first(), self.samples.last()) {
+            (Some(first), Some(last)) => last.distance - first.distance,
+            _ => 0.0,
+        }
+    }
+
+    pub fn export(&self, format: Format) -> Vec<u8> {
+        match format {
+            Format::Fit => fit::encode(self),
+            Format::Tcx => self.to_tcx().into_bytes(),
+            Format::Gpx => self.to_gpx().into_bytes(),
+        }
+    }
+
+    pub fn to_gpx(&self) -> String {
+        let mut gpx = String::from(concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<gpx version=\"1.1\" creator=\"tpvbc2http\" xmlns=\"http://www.topografix.com/GPX/1/1\" ",
+            "xmlns:gpxtpx=\"http://www.garmin.com/xmlschemas/TrackPointExtension/v1\">\n",
+        ));
+
+        let start = self.start.unwrap_or(UNIX_EPOCH);
+        let _ = writeln!(gpx, " <metadata><time>{}</time></metadata>", iso8601(start));
+        let _ = writeln!(gpx, " <trk>\n  <name>{}</name>\n  <type>VirtualRide</type>\n  <trkseg>", escape(&self.name));
+
+        for sample in &self.samples {
+            let _ = write!(gpx, "   <trkpt lat=\"{:.7}\" lon=\"{:.7}\"><ele>{:.1}</ele><time>{}</time>",
+                sample.latitude, sample.longitude, sample.height, iso8601(self.time_of(sample)));
+            let _ = write!(gpx, "<extensions><power>{:.0}</power><gpxtpx:TrackPointExtension>", sample.power);
+            if sample.heartrate > 0.0 {
+                let _ = write!(gpx, "<gpxtpx:hr>{:.0}</gpxtpx:hr>", sample.heartrate);
+            }
+            let _ = writeln!(gpx, "<gpxtpx:cad>{:.0}</gpxtpx:cad></gpxtpx:TrackPointExtension></extensions></trkpt>", sample.cadence);
+        }
+
+        gpx.push_str("  </trkseg>\n </trk>\n</gpx>\n");
+        gpx
+    }
+
+    pub fn to_tcx(&self) -> String {
+        let mut tcx = String::from(concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<TrainingCenterDatabase xmlns=\"http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2\" ",
+            "xmlns:ns3=\"http://www.garmin.com/xmlschemas/ActivityExtension/v2\">\n",
+        ));
+
+        let start = iso8601(self.start.unwrap_or(UNIX_EPOCH));
+        let _ = writeln!(tcx, " <Activities>\n  <Activity Sport=\"Biking\">\n   <Id>{}</Id>", start);
+        let _ = writeln!(tcx, "   <Lap StartTime=\"{}\">", start);
+        let _ = writeln!(tcx, "    <TotalTimeSeconds>{:.0}</TotalTimeSeconds>", self.duration());
+        let _ = writeln!(tcx, "    <DistanceMeters>{:.1}</DistanceMeters>", self.distance());
+        tcx.push_str("    <Calories>0</Calories>\n    <Intensity>Active</Intensity>\n    <TriggerMethod>Manual</TriggerMethod>\n    <Track>\n");
+
+        for sample in &self.samples {
+            let _ = write!(tcx, "     <Trackpoint><Time>{}</Time>", iso8601(self.time_of(sample)));
+            let _ = write!(tcx, "<Position><LatitudeDegrees>{:.7}</LatitudeDegrees><LongitudeDegrees>{:.7}</LongitudeDegrees></Position>",
+                sample.latitude, sample.longitude);
+            let _ = write!(tcx, "<AltitudeMeters>{:.1}</AltitudeMeters><DistanceMeters>{:.1}</DistanceMeters>", sample.height, sample.distance);
+            if sample.heartrate > 0.0 {
+                let _ = write!(tcx, "<HeartRateBpm><Value>{:.0}</Value></HeartRateBpm>", sample.heartrate);
+            }
+            let _ = write!(tcx, "<Cadence>{:.0}</Cadence>", sample.cadence.min(254.0));
+            let _ = writeln!(tcx, "<Extensions><ns3:TPX><ns3:Speed>{:.3}</ns3:Speed><ns3:Watts>{:.0}</ns3:Watts></ns3:TPX></Extensions></Trackpoint>",
+                sample.speed, sample.power);
+        }
+
+        tcx.push_str("    </Track>\n   </Lap>\n  </Activity>\n </Activities>\n</TrainingCenterDatabase>\n");
+        tcx
+    }
+}
+
+/// Collect the activity of the focus document of `cache`.
+pub fn start(cache: &Cache) -> (Arc<Mutex<Activity>>, Subscription) {
+    let activity = Arc::new(Mutex::new(Activity::new()));
+    let collecting = activity.clone();
+
+    let subscription = cache.on_update(move |update| {
+        if let Some(focus) = update.document.as_ref().and_then(|document| document.focus()) {
+            collecting.lock().unwrap().add(update.time, focus);
+        }
+    });
+
+    (activity, subscription)
+}
+
+/// UTC time like 2024-03-01T18:30:00Z.
+pub fn iso8601(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod activity_should {
+    use super::*;
+
+    fn focus(time: f64, distance: f64) -> Focus {
+        Focus { name: String::from("Joe <3"), time, distance, speed: 10_000.0, power: 200.0, cadence: 90.0, heartrate: 140.0, ..Focus::default() }
+    }
+
+    #[test]
+    fn keep_one_sample_per_second() {
+        let received = UNIX_EPOCH + Duration::from_secs(1_700_000_100);
+        let mut activity = Activity::new();
+        for (time, distance) in [(100.0, 1000.0), (100.5, 1005.0), (101.0, 1010.0), (103.2, 1032.0)] {
+            activity.add(received, &focus(time, distance));
+        }
+
+        assert_eq!(3, activity.samples.len());
+        assert_eq!(Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)), activity.start);
+        assert_eq!(10.0, activity.samples[0].speed);
+        assert_eq!(32.0, activity.distance());
+        assert!((activity.samples[2].longitude - 1032.0 / METERS_PER_DEGREE).abs() < 1e-9);
+
+        // restarts with the ride
+        activity.add(received, &focus(5.0, 50.0));
+        assert_eq!(1, activity.samples.len());
+    }
+
+    #[test]
+    fn skip_out_of_range_times() {
+        let received = UNIX_EPOCH + Duration::from_secs(1_700_000_100);
+        let mut activity = Activity::new();
+        activity.add(received, &focus(100.0, 1000.0));
+        activity.add(received, &focus(1e19, 2000.0));
+        activity.add(received, &focus(1e300, 3000.0));
+        activity.add(received, &focus(101.0, 1010.0));
+
+        assert_eq!(2, activity.samples.len());
+        assert_eq!(received + Duration::from_secs(1), activity.time_of(&activity.samples[1]));
+    }
+
+    #[test]
+    fn export_xml_formats() {
+        let mut activity = Activity::new();
+        activity.add(UNIX_EPOCH + Duration::from_secs(1_700_000_000), &focus(0.0, 0.0));
+
+        let gpx = activity.to_gpx();
+        assert!(gpx.contains("<name>Joe &lt;3</name>"));
+        assert!(gpx.contains("<time>2023-11-14T22:13:20Z</time>"));
+        assert!(gpx.contains("<power>200</power>"));
+
+        let tcx = activity.to_tcx();
+        assert!(tcx.contains("<Id>2023-11-14T22:13:20Z</Id>"));
+        assert!(tcx.contains("<ns3:Watts>200</ns3:Watts>"));
+    }
+
+    #[test]
+    fn format_dates() {
+        assert_eq!("1970-01-01T00:00:00Z", iso8601(UNIX_EPOCH));
+        assert_eq!("2024-02-29T12:34:56Z", iso8601(UNIX_EPOCH + Duration::from_secs(1_709_210_096)));
+    }
+}
@@ -0,0 +1,777 @@
+//! The broadcast documents as they are served.
+//!
+//! Every document is an immutable snapshot which is swapped atomically on a
+//! change, with the headers and compressed variants needed to serve it. Readers
+//! take the current snapshot without locking and never wait for the watcher.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use arc_swap::ArcSwap;
+use flate2::{write::GzEncoder, Compression};
+use serde_json::{json, Value};
+use crate::mapping::{Mapping, TPV_DOCUMENTS};
+use crate::model::Document;
+
+/// Outcome of offering new content to a cache slot.
+pub enum Reload {
+    /// Content replaced the cached one, the new version.
+    Updated(Arc<Versioned>),
+    /// Content is the same as the cached one.
+    Unchanged,
+    /// Content was rejected, the last good one is kept.
+    Invalid(String),
+}
+
+/// The last error reading a document, and how often it failed.
+#[derive(Clone, Default)]
+struct Health {
+    last_error: Option<String>,
+    last_error_at: Option<SystemTime>,
+    parse_failures: u64,
+    reload_failures: u64,
+}
+
+/// After how long without being written documents are stale, `None` for never.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StaleAfter {
+    pub default: Option<Duration>,
+    /// Thresholds of single documents, e.g. ones TPV rarely writes.
+    pub documents: BTreeMap<String, Option<Duration>>,
+}
+
+impl StaleAfter {
+    pub fn of(&self, name: &str) -> Option<Duration> {
+        self.documents.get(name).copied().unwrap_or(self.default)
+    }
+}
+
+/// Long-polls waiting in `wait_newer`, shared by the caches of a server.
+///
+/// Every waiter holds a thread of the server's pool, once `max` of them wait
+/// further polls get the current version at once.
+pub struct Waiters {
+    waiting: AtomicUsize,
+    max: AtomicUsize,
+}
+
+impl Default for Waiters {
+    fn default() -> Waiters {
+        Waiters { waiting: AtomicUsize::new(0), max: AtomicUsize::new(usize::MAX) }
+    }
+}
+
+impl Waiters {
+    pub fn set_max(&self, max: usize) {
+        self.max.store(max, Ordering::SeqCst);
+    }
+
+    fn enter(&self) -> bool {
+        if self.waiting.fetch_add(1, Ordering::SeqCst) < self.max.load(Ordering::SeqCst) {
+            true
+        } else {
+            self.leave();
+            false
+        }
+    }
+
+    fn leave(&self) {
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// What the watcher feeding a cache is doing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatcherState {
+    /// No directory watched, e.g. while replaying a journal.
+    Idle,
+    /// The directory does not exist (yet), checked periodically.
+    Waiting,
+    Watching,
+    Stopped,
+}
+
+impl WatcherState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            WatcherState::Idle => "idle",
+            WatcherState::Waiting => "waiting",
+            WatcherState::Watching => "watching",
+            WatcherState::Stopped => "stopped",
+        }
+    }
+}
+
+/// The watcher state with the directory, backend and errors.
+#[derive(Clone)]
+struct WatcherHealth {
+    state: WatcherState,
+    dir: Option<PathBuf>,
+    backend: Option<String>,
+    since: SystemTime,
+    errors: u64,
+    last_error: Option<String>,
+    last_error_at: Option<SystemTime>,
+}
+
+/// How often a document changed, was written and failed to load, since the start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Counters {
+    pub name: String,
+    pub updates: u64,
+    /// Signs of life from the writer, whether or not the content changed, see `seen`.
+    pub writes: u64,
+    /// Reloads given up on content which was no JSON (array for the TPV
+    /// documents), or content which did not match the typed model.
+    pub parse_failures: u64,
+    /// Reloads given up after retrying, see `Cache::failed`.
+    pub reload_failures: u64,
+}
+
+/// Content below which compressing is not worth it.
+const GZIP_MIN_LEN: usize = 256;
+
+/// A version of a document with everything needed to serve it, never
+/// changed once built.
+pub struct Versioned {
+    pub content: Arc<str>,
+    /// Incremented on every change, 0 for the initial empty array.
+    pub version: u64,
+    pub modified: SystemTime,
+    /// Entity tag for HTTP, differs between runs even for the same version.
+    pub etag: String,
+    /// `modified` as HTTP date, for `last-modified`.
+    pub last_modified: String,
+    /// `content` gzip compressed, `None` if it is too small to bother.
+    pub gzip: Option<Arc<[u8]>>,
+    /// The parsed content, `None` if it could not be parsed.
+    pub document: Option<Arc<Document>>,
+}
+
+impl Versioned {
+    fn new(content: String, version: u64, created: u64, document: Option<Arc<Document>>) -> Versioned {
+        let modified = SystemTime::now();
+        let gzip = Some(&content)
+            .filter(|content| content.len() >= GZIP_MIN_LEN)
+            .and_then(|content| {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(content.as_bytes()).ok()?;
+                encoder.finish().ok()
+            })
+            .filter(|gzip| gzip.len() < content.len())
+            .map(Arc::from);
+
+        Versioned {
+            content: Arc::from(content),
+            version,
+            modified,
+            etag: format!("\"{:x}-{}\"", created, version),
+            last_modified: httpdate::fmt_http_date(modified),
+            gzip,
+            document,
+        }
+    }
+
+    /// `content` as response body, without copying it.
+    pub fn body(&self) -> Arc<[u8]> {
+        Arc::from(self.content.clone())
+    }
+}
+
+/// A broadcast document, raw as read from disk and parsed.
+pub struct CacheableJson {
+    name: String,
+    file: String,
+    created: u64,
+    data: ArcSwap<Versioned>,
+    /// Held while replacing `data`, so changes are not lost and waiters are woken up.
+    writer: Mutex<()>,
+    changed: Condvar,
+    /// Milliseconds since the epoch, 0 if never.
+    last_good_at: AtomicU64,
+    writes: AtomicU64,
+    health: Mutex<Health>,
+}
+
+impl CacheableJson {
+    pub fn new(name: &str, file: &str) -> CacheableJson {
+        let created = epoch_ms(SystemTime::now());
+
+        CacheableJson {
+            name: name.to_string(),
+            file: file.to_string(),
+            created,
+            data: ArcSwap::from_pointee(Versioned::new(String::from("[]"), 0, created, None)),
+            writer: Mutex::new(()),
+            changed: Condvar::new(),
+            last_good_at: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            health: Mutex::new(Health::default()),
+        }
+    }
+
+    /// The current version.
+    pub fn get(&self) -> Arc<Versioned> {
+        self.data.load_full()
+    }
+
+    /// Replace the cached content if it is valid JSON, an array for the TPV
+    /// documents. Auto mapped files may hold any JSON value.
+    ///
+    /// Valid content which does not match the typed model is still cached, its document is `None` then.
+    /// Invalid content is not counted, TPV may still be writing it, see `invalid`.
+    pub fn update(&self, content: String) -> Reload {
+        let invalid = match serde_json::from_str::<Value>(&content) {
+            Ok(Value::Array(_)) => None,
+            Ok(_) if !TPV_DOCUMENTS.contains(&self.name.as_str()) => None,
+            Ok(_) => Some(String::from("not a JSON array")),
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(e) = invalid {
+            return Reload::Invalid(e);
+        }
+
+        let _writing = self.writer.lock().unwrap();
+        let current = self.data.load();
+
+        // the same content again is no sign of life, e.g. a rescan or a reattached directory
+        if *current.content == *content {
+            return Reload::Unchanged;
+        }
+        self.seen();
+
+        let document = match Document::parse(&self.name, &content) {
+            Some(Ok(document)) => Some(Arc::new(document)),
+            Some(Err(e)) => {
+                log::warn!("Failed to parse {} data: {}", self.name, e);
+                self.health.lock().unwrap().parse_failures += 1;
+                None
+            },
+            None => None,
+        };
+
+        let updated = Arc::new(Versioned::new(content, current.version + 1, self.created, document));
+        self.data.store(updated.clone());
+        self.changed.notify_all();
+        Reload::Updated(updated)
+    }
+
+    /// Record a sign of life from the writer, whether or not the content changed.
+    pub fn seen(&self) {
+        self.last_good_at.store(epoch_ms(SystemTime::now()), Ordering::Relaxed);
+        self.writes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn last_good_at(&self) -> Option<u64> {
+        Some(self.last_good_at.load(Ordering::Relaxed)).filter(|ms| *ms > 0)
+    }
+
+    /// Time since the document was last written, or since it was created if it never was.
+    pub fn age(&self) -> Duration {
+        let since = self.last_good_at().unwrap_or(self.created);
+        Duration::from_millis(epoch_ms(SystemTime::now()).saturating_sub(since))
+    }
+
+    /// Record an error while reloading, the cached content stays as it is.
+    pub fn failed(&self, error: String) {
+        let mut health_locked = self.health.lock().unwrap();
+        health_locked.last_error = Some(error);
+        health_locked.last_error_at = Some(SystemTime::now());
+        health_locked.reload_failures += 1;
+    }
+
+    /// Record a reload given up on invalid content, a parse and a reload failure.
+    pub fn invalid(&self, error: String) {
+        self.health.lock().unwrap().parse_failures += 1;
+        self.failed(error);
+    }
+
+    pub fn counters(&self) -> Counters {
+        let health = self.health.lock().unwrap();
+
+        Counters {
+            name: self.name.clone(),
+            updates: self.data.load().version,
+            writes: self.writes.load(Ordering::Relaxed),
+            parse_failures: health.parse_failures,
+            reload_failures: health.reload_failures,
+        }
+    }
+
+    /// Health of this document, times in milliseconds since the epoch, the
+    /// age in milliseconds.
+    pub fn status(&self, stale_after: Option<Duration>) -> Value {
+        let age = self.age();
+        let health = self.health.lock().unwrap().clone();
+
+        json!({
+            "name": self.name,
+            "age": age.as_millis() as u64,
+            "stale": stale_after.is_some_and(|after| age >= after),
+            "lastGoodAt": self.last_good_at(),
+            "lastError": health.last_error,
+            "lastErrorAt": health.last_error_at.map(epoch_ms),
+        })
+    }
+}
+
+/// Milliseconds since the epoch.
+pub fn epoch_ms(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Change notification sent to cache listeners.
+#[derive(Clone)]
+pub struct Update {
+    pub name: String,
+    pub time: SystemTime,
+    pub content: Arc<str>,
+    pub document: Option<Arc<Document>>,
+}
+
+type Callback = Arc<dyn Fn(&Update) + Send + Sync>;
+
+/// Identifies a callback registered with `Cache::on_update`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subscription(usize);
+
+/// Registry of broadcast documents, keyed by document name.
+///
+/// Documents from the mapping are there from the start (as an empty array),
+/// others are added when they are first updated.
+#[derive(Clone)]
+pub struct Cache {
+    listeners: Arc<Mutex<Vec<mpsc::Sender<Update>>>>,
+    closed: Arc<AtomicBool>,
+    callbacks: Arc<Mutex<Vec<(Subscription, Callback)>>>,
+    mapping: Arc<Mapping>,
+    documents: Arc<RwLock<BTreeMap<String, Arc<CacheableJson>>>>,
+    watcher: Arc<Mutex<WatcherHealth>>,
+    stale_after: Arc<RwLock<StaleAfter>>,
+    waiters: Arc<RwLock<Arc<Waiters>>>,
+}
+
+impl Default for Cache {
+    fn default() -> Cache {
+        Cache::new()
+    }
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache::with_mapping(Mapping::default())
+    }
+
+    pub fn with_mapping(mapping: Mapping) -> Cache {
+        let documents = mapping.documents.iter()
+            .map(|(name, file)| (name.clone(), Arc::new(CacheableJson::new(name, file))))
+            .collect();
+
+        Cache { 
+            listeners: Arc::new(Mutex::new(Vec::new())),
+            closed: Arc::new(AtomicBool::new(false)),
+            callbacks: Arc::new(Mutex::new(Vec::new())),
+            mapping: Arc::new(mapping),
+            documents: Arc::new(RwLock::new(documents)),
+            watcher: Arc::new(Mutex::new(WatcherHealth {
+                state: WatcherState::Idle,
+                dir: None,
+                backend: None,
+                since: SystemTime::now(),
+                errors: 0,
+                last_error: None,
+                last_error_at: None,
+            })),
+            stale_after: Arc::new(RwLock::new(StaleAfter::default())),
+            waiters: Arc::new(RwLock::new(Arc::new(Waiters::default()))),
+        }
+    }
+
+    pub fn mapping(&self) -> &Mapping {
+        &self.mapping
+    }
+
+    /// Names of all documents currently in the cache.
+    pub fn names(&self) -> Vec<String> {
+        self.documents.read().unwrap().keys().cloned().collect()
+    }
+
+    fn slot(&self, name: &str) -> Option<Arc<CacheableJson>> {
+        self.documents.read().unwrap().get(name).cloned()
+    }
+
+    fn slot_or_insert(&self, name: &str) -> Arc<CacheableJson> {
+        if let Some(slot) = self.slot(name) {
+            return slot;
+        }
+
+        let file = self.mapping.file_for(name);
+        log::info!("New document {} from {}", name, file);
+
+        self.documents.write().unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(CacheableJson::new(name, &file)))
+            .clone()
+    }
+
+    /// Document name of a broadcast file, `None` if it is not exposed.
+    pub fn name_for(&self, p: &Path) -> Option<String> {
+        self.mapping.name_for(p.file_name()?.to_str()?)
+    }
+
+    /// Current content of a broadcast document.
+    pub fn get(&self, name: &str) -> Option<Arc<str>> {
+        self.slot(name).map(|slot| slot.get().content.clone())
+    }
+
+    /// Current content of a broadcast document with its version.
+    pub fn get_versioned(&self, name: &str) -> Option<Arc<Versioned>> {
+        self.slot(name).map(|slot| slot.get())
+    }
+
+    /// Content of a broadcast document as soon as its version is newer than
+    /// `since`, or the current one after `timeout` (or when the cache is closed).
+    ///
+    /// Returns the current one at once if too many polls are waiting already.
+    pub fn wait_newer(&self, name: &str, since: u64, timeout: Duration) -> Option<Arc<Versioned>> {
+        let slot = self.slot(name)?;
+        let waiters = self.waiters.read().unwrap().clone();
+        if !waiters.enter() {
+            return Some(slot.get());
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut writing = slot.writer.lock().unwrap();
+
+        while slot.data.load().version <= since && !self.closed.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            writing = slot.changed.wait_timeout(writing, deadline - now).unwrap().0;
+        }
+        waiters.leave();
+
+        Some(slot.get())
+    }
+
+    /// Current parsed broadcast document, `None` if it is not known or could not be parsed.
+    pub fn document(&self, name: &str) -> Option<Arc<Document>> {
+        self.slot(name)?.get().document.clone()
+    }
+
+    /// Health of all broadcast documents as a JSON array.
+    pub fn status(&self) -> String {
+        let stale_after = self.stale_after.read().unwrap().clone();
+        let status: Vec<Value> = self.documents.read().unwrap().values()
+            .map(|slot| slot.status(stale_after.of(&slot.name)))
+            .collect();
+
+        Value::Array(status).to_string()
+    }
+
+    /// Mark documents stale once they were not written for a while, never by default.
+    pub fn set_stale_after(&self, stale_after: StaleAfter) {
+        *self.stale_after.write().unwrap() = stale_after;
+    }
+
+    /// Limit the long-polls waiting at the same time, unlimited by default.
+    pub fn set_waiters(&self, waiters: Arc<Waiters>) {
+        *self.waiters.write().unwrap() = waiters;
+    }
+
+    /// Time since a document was last written, `None` if it is not known.
+    pub fn age(&self, name: &str) -> Option<Duration> {
+        Some(self.slot(name)?.age())
+    }
+
+    /// Whether a document was not written for longer than its threshold.
+    pub fn is_stale(&self, name: &str) -> bool {
+        match (self.stale_after.read().unwrap().of(name), self.age(name)) {
+            (Some(after), Some(age)) => age >= after,
+            _ => false,
+        }
+    }
+
+    /// Record that TPV wrote a document again with the same content, it is not stale then.
+    pub fn seen(&self, name: &str) {
+        if let Some(slot) = self.slot(name) {
+            slot.seen();
+        }
+    }
+
+    /// Update and failure counts of all documents.
+    pub fn counters(&self) -> Vec<Counters> {
+        self.documents.read().unwrap().values().map(|slot| slot.counters()).collect()
+    }
+
+    /// Record what the watcher feeding this cache is doing, `backend` as
+    /// reported by it, e.g. "inotify" or "polling".
+    pub fn set_watcher_state(&self, dir: &Path, state: WatcherState, backend: Option<String>) {
+        let mut watcher = self.watcher.lock().unwrap();
+        watcher.state = state;
+        watcher.dir = Some(dir.to_path_buf());
+        watcher.backend = backend;
+        watcher.since = SystemTime::now();
+    }
+
+    /// Count an error reported by the watcher feeding this cache.
+    pub fn watch_failed(&self, error: String) {
+        let mut watcher = self.watcher.lock().unwrap();
+        watcher.errors += 1;
+        watcher.last_error = Some(error);
+        watcher.last_error_at = Some(SystemTime::now());
+    }
+
+    pub fn watch_errors(&self) -> u64 {
+        self.watcher.lock().unwrap().errors
+    }
+
+    pub fn watcher_state(&self) -> WatcherState {
+        self.watcher.lock().unwrap().state
+    }
+
+    /// State of the watcher feeding this cache as a JSON object, times in
+    /// milliseconds since the epoch.
+    pub fn watcher_status(&self) -> String {
+        let watcher = self.watcher.lock().unwrap().clone();
+
+        json!({
+            "state": watcher.state.as_str(),
+            "dir": watcher.dir.map(|dir| dir.display().to_string()),
+            "backend": watcher.backend,
+            "since": epoch_ms(watcher.since),
+            "errors": watcher.errors,
+            "lastError": watcher.last_error,
+            "lastErrorAt": watcher.last_error_at.map(epoch_ms),
+        }).to_string()
+    }
+
+    /// All documents with their file and route below `base` (e.g. "/bcast/") as a JSON array.
+    pub fn index(&self, base: &str) -> String {
+        let index: Vec<Value> = self.documents.read().unwrap().values()
+            .map(|slot| json!({
+                "name": slot.name,
+                "file": slot.file,
+                "url": format!("{}{}", base, slot.name),
+            }))
+            .collect();
+
+        Value::Array(index).to_string()
+    }
+
+    /// Register a listener which receives every update of the cache.
+    ///
+    /// The listener is dropped on the next update after the receiver went away.
+    pub fn subscribe(&self) -> mpsc::Receiver<Update> {
+        let (tx, rx) = mpsc::channel();
+        self.listeners.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Call `callback` on every update of the cache, on the thread doing the update.
+    ///
+    /// The callback should return quickly, it holds up the watcher.
+    pub fn on_update<F>(&self, callback: F) -> Subscription
+    where
+        F: Fn(&Update) + Send + Sync + 'static,
+    {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let subscription = Subscription(NEXT.fetch_add(1, Ordering::Relaxed));
+        self.callbacks.lock().unwrap().push((subscription, Arc::new(callback)));
+        subscription
+    }
+
+    /// Remove a callback registered with `on_update`.
+    pub fn unsubscribe(&self, subscription: Subscription) {
+        self.callbacks.lock().unwrap().retain(|(s, _)| *s != subscription);
+    }
+
+    /// Drop all listeners and callbacks, receivers see the cache disconnected
+    /// and `wait_newer` returns right away.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.listeners.lock().unwrap().clear();
+        self.callbacks.lock().unwrap().clear();
+
+        for slot in self.documents.read().unwrap().values() {
+            let _writing = slot.writer.lock().unwrap();
+            slot.changed.notify_all();
+        }
+    }
+
+    /// Offer new content for a broadcast document, listeners are notified if it changed.
+    ///
+    /// The document is added to the cache if it is not there yet.
+    pub fn update(&self, name: &str, content: String) -> Reload {
+        let slot = self.slot_or_insert(name);
+
+        let reload = slot.update(content);
+        if let Reload::Updated(updated) = &reload {
+            log::info!("Updated cache for {} data", slot.name);
+            self.publish(&slot.name, updated.content.clone(), updated.document.clone());
+        }
+        reload
+    }
+
+    /// Record an error while reloading a document.
+    pub fn failed(&self, name: &str, error: String) {
+        self.slot_or_insert(name).failed(error);
+    }
+
+    /// Record that a document was still invalid after retrying.
+    pub fn invalid(&self, name: &str, error: String) {
+        self.slot_or_insert(name).invalid(error);
+    }
+
+    fn publish(&self, name: &str, content: Arc<str>, document: Option<Arc<Document>>) {
+        let update = Update { name: name.to_string(), time: SystemTime::now(), content, document };
+        self.listeners.lock().unwrap().retain(|tx| tx.send(update.clone()).is_ok());
+
+        // called without the lock, so callbacks can (un)subscribe
+        let callbacks: Vec<Callback> = self.callbacks.lock().unwrap().iter().map(|(_, c)| c.clone()).collect();
+        for callback in callbacks {
+            callback(&update);
+        }
+    }
+}
+
+#[cfg(test)]
+mod cache_should {
+    use super::*;
+
+    #[test]
+    fn keep_last_good_content_on_invalid_json() {
+        let slot = CacheableJson::new("groups", "groups.json");
+
+        assert!(matches!(slot.update(String::from(r#"[{"size": 1}]"#)), Reload::Updated(v) if v.document.is_some()));
+        assert!(matches!(slot.update(String::from(r#"[{"size": 1}"#)), Reload::Invalid(_)));
+        assert!(matches!(slot.update(String::from(r#"{"size": 1}"#)), Reload::Invalid(_)));
+        assert_eq!(r#"[{"size": 1}]"#, &*slot.get().content);
+
+        // retries of the same write are counted once, when given up
+        let counters = slot.counters();
+        assert_eq!((1, 0, 0), (counters.updates, counters.parse_failures, counters.reload_failures));
+
+        slot.invalid(String::from("invalid content"));
+        let status = slot.status(None);
+        assert_eq!("invalid content", status["lastError"]);
+        assert!(status["lastGoodAt"].is_u64());
+
+        slot.failed(String::from("read failed"));
+        let counters = slot.counters();
+        assert_eq!((1, 1, 2), (counters.updates, counters.parse_failures, counters.reload_failures));
+    }
+
+    #[test]
+    fn accept_any_json_for_auto_mapped_documents() {
+        let cache = Cache::new();
+
+        assert!(matches!(cache.update("settings", String::from(r#"{"units": "metric"}"#)), Reload::Updated(_)));
+        assert!(matches!(cache.update("settings", String::from(r#"{"units": "#)), Reload::Invalid(_)));
+        assert!(matches!(cache.update("focus", String::from(r#"{"power": 200}"#)), Reload::Invalid(_)));
+        assert_eq!(Some(r#"{"units": "metric"}"#), cache.get("settings").as_deref());
+    }
+
+    #[test]
+    fn add_new_documents_on_update() {
+        let cache = Cache::new();
+
+        assert_eq!(Some("[]"), cache.get("focus").as_deref());
+        assert_eq!(None, cache.get("laps"));
+
+        let rx = cache.subscribe();
+        cache.update("laps", String::from("[1]"));
+
+        assert_eq!(Some("[1]"), cache.get("laps").as_deref());
+        assert_eq!("laps", rx.try_recv().unwrap().name);
+        assert!(cache.names().contains(&String::from("laps")));
+    }
+
+    #[test]
+    fn count_versions_and_wake_up_waiters() {
+        let cache = Cache::new();
+        let initial = cache.get_versioned("focus").unwrap();
+        assert_eq!(0, initial.version);
+
+        let writer = cache.clone();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            writer.update("focus", String::from("[1]"));
+        });
+
+        let newer = cache.wait_newer("focus", 0, Duration::from_secs(5)).unwrap();
+        thread.join().unwrap();
+        assert_eq!(1, newer.version);
+        assert_eq!("[1]", &*newer.content);
+        assert_ne!(initial.etag, newer.etag);
+
+        cache.update("focus", String::from("[1]"));
+        let unchanged = cache.wait_newer("focus", 1, Duration::from_millis(10)).unwrap();
+        assert_eq!(1, unchanged.version);
+    }
+
+    #[test]
+    fn answer_at_once_when_too_many_wait() {
+        let cache = Cache::new();
+        let waiters = Arc::new(Waiters::default());
+        waiters.set_max(1);
+        cache.set_waiters(waiters.clone());
+
+        let waiter = cache.clone();
+        let thread = std::thread::spawn(move || waiter.wait_newer("focus", 0, Duration::from_secs(5)).unwrap());
+        while waiters.waiting.load(Ordering::SeqCst) == 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let started = Instant::now();
+        assert_eq!(0, cache.wait_newer("focus", 0, Duration::from_secs(5)).unwrap().version);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        cache.update("focus", String::from("[1]"));
+        assert_eq!(1, thread.join().unwrap().version);
+        assert_eq!(0, waiters.waiting.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn mark_documents_stale() {
+        let cache = Cache::new();
+        let mut documents = BTreeMap::new();
+        documents.insert(String::from("event"), None);
+        cache.set_stale_after(StaleAfter { default: Some(Duration::from_millis(50)), documents });
+
+        cache.update("focus", String::from("[1]"));
+        assert!(!cache.is_stale("focus"));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(cache.is_stale("focus"));
+        assert!(!cache.is_stale("event"));
+        assert!(cache.age("focus").unwrap() >= Duration::from_millis(60));
+
+        let status: Value = serde_json::from_str(&cache.status()).unwrap();
+        let focus = status.as_array().unwrap().iter().find(|d| d["name"] == "focus").unwrap();
+        assert_eq!(true, focus["stale"]);
+
+        cache.seen("focus");
+        assert!(!cache.is_stale("focus"));
+    }
+
+    #[test]
+    fn call_back_until_unsubscribed() {
+        let cache = Cache::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let log = seen.clone();
+        let subscription = cache.on_update(move |update| log.lock().unwrap().push(update.name.clone()));
+        cache.update("focus", String::from("[1]"));
+        cache.update("focus", String::from("[1]"));
+        cache.unsubscribe(subscription);
+        cache.update("focus", String::from("[2]"));
+
+        assert_eq!(vec![String::from("focus")], *seen.lock().unwrap());
+    }
+}
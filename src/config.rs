@@ -0,0 +1,382 @@
+//! Settings from the configuration file.
+//!
+//! Every setting has a default, so a configuration file only needs to contain
+//! what differs. Command line flags and environment variables override values
+//! from the file (see `Args` in main.rs).
+
+use std::{env, fs};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use crate::cache::StaleAfter;
+use crate::mapping::Mapping;
+use crate::server::StalePayload;
+use crate::source::WatcherBackend;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the HTTP server binds to.
+    pub bind: String,
+    pub port: u16,
+    /// Threads handling connections, 0 for one per logical core.
+    pub threads: u32,
+    /// TrainingPeaks Virtual broadcast directory, empty for the testing data.
+    pub tpvbcdir: String,
+    /// Static HTML directory, empty to not serve static files.
+    pub statdir: String,
+    /// Seconds between full rescans of the broadcast directory, 0 to disable.
+    pub rescan: u64,
+    /// Milliseconds to wait for further changes of a broadcast file before reloading it.
+    pub debounce_ms: u64,
+    /// Seconds responses in progress may take to finish on shutdown.
+    pub shutdown_timeout: u64,
+    pub watcher: WatcherConfig,
+    pub stale: StaleConfig,
+    /// Further broadcast directories by name, e.g. one per rider of a team.
+    pub sources: BTreeMap<String, String>,
+    pub rider: Rider,
+    pub log: LogConfig,
+    pub endpoints: Endpoints,
+    pub mapping: MappingConfig,
+    pub record: RecordConfig,
+    pub replay: ReplayConfig,
+}
+
+/// The rider, for metrics relative to their abilities.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rider {
+    /// Functional threshold power in W, 0 if unknown.
+    pub ftp: f64,
+    /// Weight in kg, 0 if unknown.
+    pub weight: f64,
+    /// Critical power in W for W'bal, 0 to use the FTP.
+    pub cp: f64,
+    /// Work capacity above CP in J, 0 to not track W'bal.
+    pub w_prime: f64,
+    /// Maximum heart rate in bpm, 0 if unknown.
+    pub max_hr: f64,
+    /// Upper bounds of the power zones in percent of FTP, the last zone is open.
+    pub power_zones: Vec<f64>,
+    /// Upper bounds of the heart rate zones in percent of the maximum heart rate.
+    pub hr_zones: Vec<f64>,
+}
+
+impl Default for Rider {
+    fn default() -> Rider {
+        Rider {
+            ftp: 0.0,
+            weight: 0.0,
+            cp: 0.0,
+            w_prime: 0.0,
+            max_hr: 0.0,
+            // Coggan
+            power_zones: vec![55.0, 75.0, 90.0, 105.0, 120.0, 150.0],
+            hr_zones: vec![60.0, 70.0, 80.0, 90.0],
+        }
+    }
+}
+
+/// How broadcast directories are watched.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatcherConfig {
+    /// "native" for file system events, "polling" for network shares which
+    /// deliver none, or "auto" to poll once no events arrived for a while.
+    pub backend: String,
+    /// Milliseconds between comparisons of the directory when polling.
+    pub poll_interval_ms: u64,
+    /// Seconds without events before "auto" falls back to polling.
+    pub fallback_secs: u64,
+}
+
+/// TPV documents only written when the event changes, never stale unless set in `[stale.documents]`.
+const RARELY_WRITTEN: [&str; 5] = ["event", "entries", "groups", "resultsIndv", "resultsTeam"];
+
+/// When documents are stale, e.g. because TPV was closed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StaleConfig {
+    /// Seconds without TPV writing a document before it is stale, 0 for never.
+    pub after_secs: u64,
+    /// What is served for stale documents: "last" content, "empty" array or "offline" marker.
+    pub payload: String,
+    /// Seconds of single documents, e.g. `nearest = 30`. Event, entries, groups
+    /// and the results are never stale unless they are set here.
+    pub documents: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Filter in `RUST_LOG` syntax, e.g. "info" or "warn,tpvbc2http=debug".
+    pub level: String,
+}
+
+/// Routes which are served, disabled ones answer with 404.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Endpoints {
+    /// `/bcast/`, the list of documents.
+    pub index: bool,
+    /// `/bcast/<name>`, the documents themselves.
+    pub documents: bool,
+    /// `/bcast/status` and `/bcast/watcher`, health of the documents and the watcher.
+    pub status: bool,
+    /// `/bcast/stream`, server-sent events.
+    pub stream: bool,
+    /// `/bcast/ws`, WebSocket.
+    pub ws: bool,
+    /// `/replay/`, controls while replaying a journal.
+    pub replay: bool,
+    /// `/export/activity.<fit|tcx|gpx>`, the ride as training file.
+    pub export: bool,
+    /// `/metrics`, telemetry and server internals for Prometheus.
+    pub prometheus: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MappingConfig {
+    /// Mapping file merged on top of the entries below, empty for none.
+    pub file: String,
+    /// Expose every *.json file not mapped explicitly under its file stem.
+    pub auto: bool,
+    /// Document name to file name, merged into the built-in mapping.
+    pub documents: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordConfig {
+    /// Journal directory, empty to not record. Named sources are recorded to `<dir>/<name>`.
+    pub dir: String,
+    /// Size in MB after which the journal is rotated.
+    pub max_mb: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplayConfig {
+    /// Journal to replay instead of watching the broadcast directory, empty for none.
+    pub journal: String,
+    pub speed: f64,
+    #[serde(rename = "loop")]
+    pub looping: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: String::from("0.0.0.0"),
+            port: 8080,
+            threads: 0,
+            tpvbcdir: String::new(),
+            statdir: String::new(),
+            rescan: 5,
+            debounce_ms: 50,
+            shutdown_timeout: 5,
+            watcher: WatcherConfig::default(),
+            stale: StaleConfig::default(),
+            sources: BTreeMap::new(),
+            rider: Rider::default(),
+            log: LogConfig::default(),
+            endpoints: Endpoints::default(),
+            mapping: MappingConfig::default(),
+            record: RecordConfig::default(),
+            replay: ReplayConfig::default(),
+        }
+    }
+}
+
+impl Default for WatcherConfig {
+    fn default() -> WatcherConfig {
+        WatcherConfig { backend: String::from("native"), poll_interval_ms: 1000, fallback_secs: 30 }
+    }
+}
+
+impl Default for StaleConfig {
+    fn default() -> StaleConfig {
+        StaleConfig { after_secs: 10, payload: String::from("last"), documents: BTreeMap::new() }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig { level: String::from("info") }
+    }
+}
+
+impl Default for Endpoints {
+    fn default() -> Endpoints {
+        Endpoints { index: true, documents: true, status: true, stream: true, ws: true, replay: true, export: true, prometheus: true }
+    }
+}
+
+impl Default for MappingConfig {
+    fn default() -> MappingConfig {
+        MappingConfig { file: String::new(), auto: true, documents: BTreeMap::new() }
+    }
+}
+
+impl Default for RecordConfig {
+    fn default() -> RecordConfig {
+        RecordConfig { dir: String::new(), max_mb: 64 }
+    }
+}
+
+impl Default for ReplayConfig {
+    fn default() -> ReplayConfig {
+        ReplayConfig { journal: String::new(), speed: 1.0, looping: false }
+    }
+}
+
+impl Config {
+    /// Read a configuration file.
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        toml::from_str(&content).map_err(|e| e.to_string())
+    }
+
+    /// Per-user configuration file, used when no file is given explicitly.
+    ///
+    /// `%APPDATA%\tpvbc2http\config.toml` on Windows, else
+    /// `$XDG_CONFIG_HOME/tpvbc2http/config.toml` or `~/.config/tpvbc2http/config.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = if cfg!(windows) {
+            PathBuf::from(env::var_os("APPDATA")?)
+        } else {
+            match env::var_os("XDG_CONFIG_HOME") {
+                Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+                _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+            }
+        };
+
+        Some(dir.join("tpvbc2http").join("config.toml"))
+    }
+
+    /// Effective settings, as they could be written to a configuration file.
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).unwrap()
+    }
+
+    /// Built-in mapping with the entries and the mapping file from the configuration.
+    pub fn mapping(&self) -> Result<Mapping, String> {
+        let mapping = Mapping::default().merge(Some(self.mapping.auto), self.mapping.documents.clone())?;
+
+        if self.mapping.file.is_empty() {
+            Ok(mapping)
+        } else {
+            mapping.merge_file(Path::new(&self.mapping.file))
+                .map_err(|e| format!("mapping {}: {}", self.mapping.file, e))
+        }
+    }
+
+    /// Thresholds for stale documents.
+    pub fn stale_after(&self) -> StaleAfter {
+        let secs = |secs: u64| Some(Duration::from_secs(secs)).filter(|_| secs > 0);
+
+        StaleAfter {
+            default: secs(self.stale.after_secs),
+            documents: RARELY_WRITTEN.iter().map(|name| (name.to_string(), None))
+                .chain(self.stale.documents.iter().map(|(name, after)| (name.clone(), secs(*after))))
+                .collect(),
+        }
+    }
+
+    /// What is served for stale documents.
+    pub fn stale_payload(&self) -> Result<StalePayload, String> {
+        match self.stale.payload.as_str() {
+            "last" => Ok(StalePayload::Last),
+            "empty" => Ok(StalePayload::Empty),
+            "offline" => Ok(StalePayload::Offline),
+            other => Err(format!("unknown stale payload {}, expected last, empty or offline", other)),
+        }
+    }
+
+    /// The configured watcher backend.
+    pub fn watcher(&self) -> Result<WatcherBackend, String> {
+        let poll_interval = Duration::from_millis(self.watcher.poll_interval_ms.max(1));
+        match self.watcher.backend.as_str() {
+            "native" => Ok(WatcherBackend::Native),
+            "polling" => Ok(WatcherBackend::Polling(poll_interval)),
+            "auto" => Ok(WatcherBackend::Auto { poll_interval, fallback: Duration::from_secs(self.watcher.fallback_secs) }),
+            other => Err(format!("unknown watcher backend {}, expected native, polling or auto", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod config_should {
+    use super::*;
+
+    #[test]
+    fn fill_missing_settings_with_defaults() {
+        let config: Config = toml::from_str("port = 9090\n[endpoints]\nws = false\n[replay]\nloop = true\n").unwrap();
+
+        assert_eq!(9090, config.port);
+        assert_eq!("0.0.0.0", config.bind);
+        assert!(!config.endpoints.ws);
+        assert!(config.endpoints.stream);
+        assert!(config.replay.looping);
+        assert_eq!(64, config.record.max_mb);
+    }
+
+    #[test]
+    fn reject_ports_out_of_range() {
+        assert!(toml::from_str::<Config>("port = 70000\n").is_err());
+    }
+
+    #[test]
+    fn round_trip_effective_settings() {
+        let mut config = Config::default();
+        config.mapping.documents.insert(String::from("focus"), String::from("rider.json"));
+        config.sources.insert(String::from("alice"), String::from("//pc1/Broadcast"));
+
+        let printed: Config = toml::from_str(&config.to_toml()).unwrap();
+        assert_eq!(config.to_toml(), printed.to_toml());
+        assert_eq!(Some(String::from("focus")), printed.mapping().unwrap().name_for("rider.json"));
+    }
+
+    #[test]
+    fn select_watcher_backend() {
+        let config: Config = toml::from_str("[watcher]\nbackend = \"auto\"\npoll_interval_ms = 500\n").unwrap();
+        assert_eq!(
+            Ok(WatcherBackend::Auto { poll_interval: Duration::from_millis(500), fallback: Duration::from_secs(30) }),
+            config.watcher(),
+        );
+        assert_eq!(Ok(WatcherBackend::Native), Config::default().watcher());
+
+        let config: Config = toml::from_str("[watcher]\nbackend = \"inotify\"\n").unwrap();
+        assert!(config.watcher().is_err());
+    }
+
+    #[test]
+    fn mark_documents_stale_unless_disabled() {
+        let config: Config = toml::from_str("[stale]\npayload = \"offline\"\n[stale.documents]\nnearest = 0\nevent = 60\n").unwrap();
+
+        let stale_after = config.stale_after();
+        assert_eq!(Some(Duration::from_secs(10)), stale_after.of("focus"));
+        assert_eq!(None, stale_after.of("nearest"));
+        assert_eq!(Some(Duration::from_secs(60)), stale_after.of("event"));
+        assert_eq!(None, stale_after.of("groups"));
+        assert_eq!(Ok(StalePayload::Offline), config.stale_payload());
+    }
+
+    #[test]
+    fn leave_event_fresh_by_default() {
+        let stale_after = Config::default().stale_after();
+
+        assert_eq!(Some(Duration::from_secs(10)), stale_after.of("focus"));
+        for name in ["event", "entries", "groups", "resultsIndv", "resultsTeam"] {
+            assert_eq!(None, stale_after.of(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn reject_unknown_settings() {
+        assert!(toml::from_str::<Config>("prot = 9090").is_err());
+    }
+}
@@ -0,0 +1,160 @@
+//! Export of recorded journals, e.g. to upload a ride afterwards or to
+//! analyse a race in a spreadsheet.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use serde_json::Value;
+use tpvbc2http::activity::{self, Activity, Format};
+use tpvbc2http::recorder::{self, Record};
+use tpvbc2http::table::Table;
+
+#[derive(clap::Args, Debug)]
+pub struct Options {
+    /// Journal to export, later parts of the same session are read as well
+    journal: String,
+
+    /// fit, tcx or gpx for the ride, csv for tables of the session [default: from the output file, else fit]
+    #[arg(short, long)]
+    format: Option<String>,
+
+    /// File to write, the directory for csv [default: next to the journal, named like it]
+    #[arg(short, long)]
+    output: Option<String>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Write the last activity in the journal to a training file, or the session to CSV tables.
+pub fn run(options: Options) -> io::Result<()> {
+    let extension = options.format.clone()
+        .or_else(|| options.output.as_ref().and_then(|output| Path::new(output).extension()).map(|ext| ext.to_string_lossy().to_lowercase()))
+        .unwrap_or_else(|| String::from("fit"));
+    let format = Format::from_extension(&extension);
+    if format.is_none() && extension != "csv" {
+        return Err(invalid(format!("unknown format {}, expected fit, tcx, gpx or csv", extension)));
+    }
+
+    let journal = PathBuf::from(&options.journal);
+    let records = recorder::read_journal(&journal)?;
+
+    let output = match options.output {
+        Some(output) => PathBuf::from(output),
+        None => {
+            let name = journal.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            let stem = name.strip_suffix(".jsonl.gz").unwrap_or(name);
+            match format {
+                Some(format) => journal.with_file_name(format!("{}.{}", stem, format.extension())),
+                None => journal.with_file_name(stem),
+            }
+        },
+    };
+
+    match format {
+        Some(format) => export_activity(&records, format, &journal, &output),
+        None => export_tables(&records, &output),
+    }
+}
+
+fn export_activity(records: &[Record], format: Format, journal: &Path, output: &Path) -> io::Result<()> {
+    let activity = Activity::from_records(records);
+    if activity.samples.is_empty() {
+        return Err(invalid(format!("no focus samples in {}", journal.display())));
+    }
+
+    fs::write(output, activity.export(format))?;
+    log::info!("Exported {} ({} samples, {:.0}s) to {}", activity.name, activity.samples.len(), activity.duration(), output.display());
+    Ok(())
+}
+
+fn export_tables(records: &[Record], dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    for (name, table) in tables(records) {
+        if table.is_empty() {
+            continue;
+        }
+
+        let path = dir.join(format!("{}.csv", name));
+        fs::write(&path, table.to_csv())?;
+        log::info!("Exported {} rows to {}", table.rows.len(), path.display());
+    }
+    Ok(())
+}
+
+/// The focus time series, the results at every location and the groups over time.
+fn tables(records: &[Record]) -> Vec<(&'static str, Table)> {
+    let mut focus = Table::new();
+    let mut groups = Table::new();
+    // latest result of every rider / team at every location
+    let mut individual: BTreeMap<(i64, String), Value> = BTreeMap::new();
+    let mut team: BTreeMap<(i64, String), Value> = BTreeMap::new();
+
+    for record in records {
+        let items = match serde_json::from_str::<Value>(&record.raw) {
+            Ok(Value::Array(items)) => items,
+            _ => continue,
+        };
+        let received = [("received", Value::String(activity::iso8601(UNIX_EPOCH + Duration::from_millis(record.time))))];
+        let key = |item: &Value, field: &str| (
+            item["location"].as_i64().unwrap_or_default(),
+            item[field].as_str().unwrap_or_default().to_string(),
+        );
+
+        match record.name.as_str() {
+            "focus" => items.iter().for_each(|item| focus.add(&received, item)),
+            "groups" => items.iter().for_each(|item| groups.add(&received, item)),
+            "resultsIndv" => individual.extend(items.into_iter().map(|item| (key(&item, "name"), item))),
+            "resultsTeam" => team.extend(items.into_iter().map(|item| (key(&item, "team"), item))),
+            _ => (),
+        }
+    }
+
+    vec![
+        ("focus", focus),
+        ("resultsIndv", results(individual.into_values().collect())),
+        ("resultsTeam", results(team.into_values().collect())),
+        ("groups", groups),
+    ]
+}
+
+/// Results ordered by location and position.
+fn results(mut items: Vec<Value>) -> Table {
+    items.sort_by_key(|item| (item["location"].as_i64().unwrap_or_default(), item["position"].as_i64().unwrap_or_default()));
+
+    let mut table = Table::new();
+    for item in &items {
+        table.add(&[], item);
+    }
+    table
+}
+
+#[cfg(test)]
+mod export_should {
+    use super::*;
+
+    fn record(time: u64, name: &str, raw: &str) -> Record {
+        Record { time, name: String::from(name), raw: String::from(raw) }
+    }
+
+    #[test]
+    fn keep_latest_results_per_location() {
+        let records = [
+            record(1000, "resultsIndv", r#"[{"location":1,"position":1,"name":"Ann"}]"#),
+            record(2000, "focus", r#"[{"name":"Ann","power":200}]"#),
+            record(3000, "resultsIndv", r#"[{"location":2,"position":2,"name":"Ann"},{"location":2,"position":1,"name":"Bob"},{"location":1,"position":2,"name":"Bob"}]"#),
+        ];
+        let tables = tables(&records);
+
+        assert_eq!("received,name,power\r\n1970-01-01T00:00:02Z,Ann,200\r\n", tables[0].1.to_csv());
+        assert_eq!(
+            "location,position,name\r\n1,1,Ann\r\n1,2,Bob\r\n2,1,Bob\r\n2,2,Ann\r\n",
+            tables[1].1.to_csv(),
+        );
+        assert!(tables[2].1.is_empty());
+    }
+}
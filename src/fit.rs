@@ -0,0 +1,358 @@
+//! Minimal writer for FIT activity files.
+//!
+//! Just enough of the FIT protocol for a single-lap ride: file id, start and
+//! stop events, one record per sample, lap, session and activity. All
+//! messages are little endian, each message type uses its own local type.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::activity::Activity;
+
+/// 1989-12-31T00:00:00Z, the FIT epoch, in seconds since the UNIX epoch.
+const FIT_EPOCH: u64 = 631_065_600;
+const PROFILE_VERSION: u16 = 2132;
+
+// global message numbers
+const FILE_ID: u16 = 0;
+const SESSION: u16 = 18;
+const LAP: u16 = 19;
+const RECORD: u16 = 20;
+const EVENT: u16 = 21;
+const ACTIVITY: u16 = 34;
+
+// field numbers shared by most messages
+const TIMESTAMP: u8 = 253;
+
+// enum values
+const FILE_ACTIVITY: u8 = 4;
+const MANUFACTURER_DEVELOPMENT: u16 = 255;
+const SPORT_CYCLING: u8 = 2;
+const SUB_SPORT_VIRTUAL_ACTIVITY: u8 = 58;
+const EVENT_TIMER: u8 = 0;
+const EVENT_LAP: u8 = 9;
+const EVENT_ACTIVITY: u8 = 26;
+const EVENT_TYPE_START: u8 = 0;
+const EVENT_TYPE_STOP: u8 = 1;
+const EVENT_TYPE_STOP_ALL: u8 = 4;
+
+/// A field value, the variant decides the FIT base type.
+#[derive(Clone, Copy)]
+enum Value {
+    Enum(u8),
+    U8(u8),
+    U16(u16),
+    S32(i32),
+    U32(u32),
+    U32z(u32),
+}
+
+impl Value {
+    fn base_type(&self) -> u8 {
+        match self {
+            Value::Enum(_) => 0x00,
+            Value::U8(_) => 0x02,
+            Value::U16(_) => 0x84,
+            Value::S32(_) => 0x85,
+            Value::U32(_) => 0x86,
+            Value::U32z(_) => 0x8c,
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match *self {
+            Value::Enum(v) | Value::U8(v) => out.push(v),
+            Value::U16(v) => out.extend_from_slice(&v.to_le_bytes()),
+            Value::S32(v) => out.extend_from_slice(&v.to_le_bytes()),
+            Value::U32(v) | Value::U32z(v) => out.extend_from_slice(&v.to_le_bytes()),
+        }
+    }
+
+    fn size(&self) -> u8 {
+        match self {
+            Value::Enum(_) | Value::U8(_) => 1,
+            Value::U16(_) => 2,
+            Value::S32(_) | Value::U32(_) | Value::U32z(_) => 4,
+        }
+    }
+}
+
+/// Messages of a FIT file, without header and CRC.
+struct Messages {
+    data: Vec<u8>,
+    /// Global message number defined for each local type.
+    defined: Vec<u16>,
+}
+
+impl Messages {
+    /// Append a message, preceded by its definition the first time.
+    fn write(&mut self, global: u16, fields: &[(u8, Value)]) {
+        let local = match self.defined.iter().position(|g| *g == global) {
+            Some(local) => local as u8,
+            None => {
+                let local = self.defined.len() as u8;
+                self.defined.push(global);
+
+                self.data.extend_from_slice(&[0x40 | local, 0, 0]);
+                self.data.extend_from_slice(&global.to_le_bytes());
+                self.data.push(fields.len() as u8);
+                for (number, value) in fields {
+                    self.data.extend_from_slice(&[*number, value.size(), value.base_type()]);
+                }
+                local
+            },
+        };
+
+        self.data.push(local);
+        for (_, value) in fields {
+            value.write(&mut self.data);
+        }
+    }
+}
+
+fn timestamp(time: SystemTime) -> u32 {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    secs.saturating_sub(FIT_EPOCH) as u32
+}
+
+fn semicircles(degrees: f64) -> i32 {
+    (degrees * (2f64.powi(31) / 180.0)).round() as i32
+}
+
+/// Value in the units of a field, casting saturates at the invalid value.
+fn scaled(value: f64, scale: f64) -> f64 {
+    (value * scale).round().max(0.0)
+}
+
+fn average(values: impl Iterator<Item = f64>) -> f64 {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+    if count > 0 { sum / count as f64 } else { 0.0 }
+}
+
+fn maximum(values: impl Iterator<Item = f64>) -> f64 {
+    values.fold(0.0, f64::max)
+}
+
+/// Heart rate in bpm, the invalid value if there is none.
+fn heartrate(bpm: f64) -> Value {
+    Value::U8(if bpm > 0.0 { scaled(bpm, 1.0) as u8 } else { u8::MAX })
+}
+
+/// Encode an activity as FIT file.
+pub fn encode(activity: &Activity) -> Vec<u8> {
+    let mut messages = Messages { data: Vec::new(), defined: Vec::new() };
+
+    let start = activity.samples.first().map(|sample| timestamp(activity.time_of(sample))).unwrap_or_default();
+    let end = activity.samples.last().map(|sample| timestamp(activity.time_of(sample))).unwrap_or(start);
+    let elapsed = Value::U32(scaled(activity.duration(), 1000.0) as u32);
+    let distance = Value::U32(scaled(activity.distance(), 100.0) as u32);
+
+    messages.write(FILE_ID, &[
+        (0, Value::Enum(FILE_ACTIVITY)),
+        (1, Value::U16(MANUFACTURER_DEVELOPMENT)),
+        (2, Value::U16(0)),
+        (3, Value::U32z(start)),
+        (4, Value::U32(start)),
+    ]);
+    messages.write(EVENT, &[
+        (TIMESTAMP, Value::U32(start)),
+        (0, Value::Enum(EVENT_TIMER)),
+        (1, Value::Enum(EVENT_TYPE_START)),
+    ]);
+
+    for sample in &activity.samples {
+        messages.write(RECORD, &[
+            (TIMESTAMP, Value::U32(timestamp(activity.time_of(sample)))),
+            (0, Value::S32(semicircles(sample.latitude))),
+            (1, Value::S32(semicircles(sample.longitude))),
+            (2, Value::U16(scaled(sample.height + 500.0, 5.0) as u16)),
+            (3, heartrate(sample.heartrate)),
+            (4, Value::U8(scaled(sample.cadence, 1.0) as u8)),
+            (5, Value::U32(scaled(sample.distance, 100.0) as u32)),
+            (6, Value::U16(scaled(sample.speed, 1000.0) as u16)),
+            (7, Value::U16(scaled(sample.power, 1.0) as u16)),
+        ]);
+    }
+
+    messages.write(EVENT, &[
+        (TIMESTAMP, Value::U32(end)),
+        (0, Value::Enum(EVENT_TIMER)),
+        (1, Value::Enum(EVENT_TYPE_STOP_ALL)),
+    ]);
+
+    let samples = &activity.samples;
+    let heartrates = || samples.iter().map(|s| s.heartrate).filter(|hr| *hr > 0.0);
+    let summary = [
+        (14, Value::U16(scaled(average(samples.iter().map(|s| s.speed)), 1000.0) as u16)),
+        (15, Value::U16(scaled(maximum(samples.iter().map(|s| s.speed)), 1000.0) as u16)),
+        (16, heartrate(average(heartrates()))),
+        (17, heartrate(maximum(heartrates()))),
+        (18, Value::U8(scaled(average(samples.iter().map(|s| s.cadence)), 1.0) as u8)),
+        (20, Value::U16(scaled(average(samples.iter().map(|s| s.power)), 1.0) as u16)),
+        (21, Value::U16(scaled(maximum(samples.iter().map(|s| s.power)), 1.0) as u16)),
+    ];
+
+    // lap and session share most fields, but the lap numbers them one lower
+    let mut lap = vec![
+        (TIMESTAMP, Value::U32(end)),
+        (0, Value::Enum(EVENT_LAP)),
+        (1, Value::Enum(EVENT_TYPE_STOP)),
+        (2, Value::U32(start)),
+        (7, elapsed),
+        (8, elapsed),
+        (9, distance),
+        (25, Value::Enum(SPORT_CYCLING)),
+    ];
+    lap.extend(summary.iter().map(|(number, value)| (number - 1, *value)));
+    messages.write(LAP, &lap);
+
+    let mut session = vec![
+        (TIMESTAMP, Value::U32(end)),
+        (0, Value::Enum(EVENT_LAP)),
+        (1, Value::Enum(EVENT_TYPE_STOP)),
+        (2, Value::U32(start)),
+        (5, Value::Enum(SPORT_CYCLING)),
+        (6, Value::Enum(SUB_SPORT_VIRTUAL_ACTIVITY)),
+        (7, elapsed),
+        (8, elapsed),
+        (9, distance),
+        (25, Value::U16(0)),
+        (26, Value::U16(1)),
+    ];
+    session.extend(summary);
+    messages.write(SESSION, &session);
+
+    messages.write(ACTIVITY, &[
+        (TIMESTAMP, Value::U32(end)),
+        (0, elapsed),
+        (1, Value::U16(1)),
+        (2, Value::Enum(0)),
+        (3, Value::Enum(EVENT_ACTIVITY)),
+        (4, Value::Enum(EVENT_TYPE_STOP)),
+    ]);
+
+    let mut file = Vec::with_capacity(messages.data.len() + 16);
+    file.extend_from_slice(&[14, 0x20]);
+    file.extend_from_slice(&PROFILE_VERSION.to_le_bytes());
+    file.extend_from_slice(&(messages.data.len() as u32).to_le_bytes());
+    file.extend_from_slice(b".FIT");
+    file.extend_from_slice(&crc(&file).to_le_bytes());
+    file.extend_from_slice(&messages.data);
+    file.extend_from_slice(&crc(&file).to_le_bytes());
+    file
+}
+
+/// FIT flavour of CRC-16.
+fn crc(bytes: &[u8]) -> u16 {
+    const TABLE: [u16; 16] = [
+        0x0000, 0xcc01, 0xd801, 0x1400, 0xf001, 0x3c00, 0x2800, 0xe401,
+        0xa001, 0x6c00, 0x7800, 0xb401, 0x5000, 0x9c01, 0x8801, 0x4400,
+    ];
+
+    bytes.iter().fold(0, |crc, byte| {
+        let crc = (crc >> 4) ^ TABLE[(crc & 0xf) as usize] ^ TABLE[(byte & 0xf) as usize];
+        (crc >> 4) ^ TABLE[(crc & 0xf) as usize] ^ TABLE[(byte >> 4) as usize]
+    })
+}
+
+#[cfg(test)]
+mod fit_should {
+    use super::*;
+    use crate::model::Focus;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    /// Data messages as global message number and field values, read back
+    /// with the definitions in the file.
+    fn decode(file: &[u8]) -> Vec<(u16, BTreeMap<u8, u64>)> {
+        let mut definitions: BTreeMap<u8, (u16, Vec<(u8, u8)>)> = BTreeMap::new();
+        let mut messages = Vec::new();
+        let mut data = &file[14..file.len() - 2];
+
+        while let Some((&header, rest)) = data.split_first() {
+            let local = header & 0x0f;
+            if header & 0x40 != 0 {
+                let global = u16::from_le_bytes([rest[2], rest[3]]);
+                let fields = rest[5..5 + 3 * rest[4] as usize].chunks(3).map(|f| (f[0], f[1])).collect();
+                data = &rest[5 + 3 * rest[4] as usize..];
+                definitions.insert(local, (global, fields));
+            } else {
+                let (global, fields) = &definitions[&local];
+                let mut values = BTreeMap::new();
+                let mut rest = rest;
+                for (number, size) in fields {
+                    let (bytes, tail) = rest.split_at(*size as usize);
+                    values.insert(*number, bytes.iter().rev().fold(0, |v, b| v << 8 | *b as u64));
+                    rest = tail;
+                }
+                messages.push((*global, values));
+                data = rest;
+            }
+        }
+        messages
+    }
+
+    fn ride(heartrate: f64) -> Activity {
+        let mut activity = Activity::new();
+        for time in 0..10 {
+            let focus = Focus {
+                name: String::from("Joe"),
+                time: time as f64,
+                distance: time as f64 * 10.0,
+                power: 200.0 + time as f64,
+                heartrate: if heartrate > 0.0 { heartrate + time as f64 } else { 0.0 },
+                ..Focus::default()
+            };
+            activity.add(UNIX_EPOCH + Duration::from_secs(1_700_000_000 + time), &focus);
+        }
+        activity
+    }
+
+    #[test]
+    fn write_a_record_per_sample() {
+        let activity = ride(120.0);
+        let messages = decode(&encode(&activity));
+
+        let records: Vec<&BTreeMap<u8, u64>> = messages.iter().filter(|(g, _)| *g == RECORD).map(|(_, f)| f).collect();
+        assert_eq!(activity.samples.len(), records.len());
+        for (sample, record) in activity.samples.iter().zip(records) {
+            assert_eq!(timestamp(activity.time_of(sample)) as u64, record[&TIMESTAMP]);
+            assert_eq!(sample.power as u64, record[&7]);
+            assert_eq!(sample.heartrate as u64, record[&3]);
+            assert_eq!((sample.distance * 100.0) as u64, record[&5]);
+        }
+
+        let session = &messages.iter().find(|(g, _)| *g == SESSION).unwrap().1;
+        assert_eq!((125, 129), (session[&16], session[&17]));
+        assert_eq!((205, 209), (session[&20], session[&21]));
+    }
+
+    #[test]
+    fn mark_missing_heartrate_invalid() {
+        let messages = decode(&encode(&ride(0.0)));
+
+        for (global, fields) in &messages {
+            match *global {
+                RECORD => assert_eq!(0xff, fields[&3]),
+                LAP => assert_eq!((0xff, 0xff), (fields[&15], fields[&16])),
+                SESSION => assert_eq!((0xff, 0xff), (fields[&16], fields[&17])),
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn checksum_header_and_file() {
+        let mut activity = Activity::new();
+        for time in 0..10 {
+            let focus = Focus { name: String::from("Joe"), time: time as f64, distance: time as f64 * 10.0, power: 200.0, ..Focus::default() };
+            activity.add(UNIX_EPOCH + Duration::from_secs(1_700_000_000 + time), &focus);
+        }
+
+        let file = encode(&activity);
+        assert_eq!(b".FIT", &file[8..12]);
+        assert_eq!(file.len() - 16, u32::from_le_bytes(file[4..8].try_into().unwrap()) as usize);
+
+        // the CRC over data and CRC is 0
+        assert_eq!(0, crc(&file[..14]));
+        assert_eq!(0, crc(&file));
+    }
+}
@@ -0,0 +1,37 @@
+//! Serve TrainingPeaks Virtual broadcast files (JSON) via HTTP.
+//!
+//! The pieces can be used on their own: a `BroadcastSource` watches the TPV
+//! broadcast directory and feeds a `Cache`, which tells subscribers about
+//! every update. `server::Instance` puts both behind an HTTP server.
+//!
+//! ```no_run
+//! use tpvbc2http::{BroadcastSource, Cache};
+//!
+//! let cache = Cache::new();
+//! cache.on_update(|update| println!("{} changed", update.name));
+//!
+//! let source = BroadcastSource::new("Broadcast").start(&cache).unwrap();
+//! // ...
+//! source.stop();
+//! ```
+
+pub mod activity;
+pub mod cache;
+pub mod config;
+mod fit;
+pub mod mapping;
+pub mod metrics;
+pub mod model;
+mod prometheus;
+pub mod recorder;
+pub mod replay;
+pub mod server;
+pub mod source;
+mod sse;
+pub mod table;
+pub mod team;
+mod ws;
+
+pub use cache::{Cache, Reload, Update};
+pub use server::{Handle, Instance};
+pub use source::{BroadcastSource, SourceHandle};
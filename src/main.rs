@@ -1,50 +1,339 @@
-use std::{env, process};
-use ctrlc;
-use clap::Parser;
+use std::{env, path::Path, process, time::Duration};
+use std::sync::atomic::{AtomicBool, Ordering};
+use clap::{Parser, Subcommand};
 use log::info;
+use tpvbc2http::config::Config;
+use tpvbc2http::{recorder, replay, server};
 
 /// Serve TraingPeaks Virtual broadcast files (JSON) via HTTP.
+///
+/// Settings are read from a TOML configuration file, flags and environment
+/// variables override the values from the file.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Port on which to run the HTTP server
-    #[arg(short, long, default_value_t = 8080)]
-    port: u32,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Configuration file [default: per-user config.toml, if it exists]
+    #[arg(short, long, env = "TPVBC2HTTP_CONFIG")]
+    config: Option<String>,
+
+    /// Print the effective configuration and exit
+    #[arg(long)]
+    print_config: bool,
+
+    /// Address on which to run the HTTP server [default: 0.0.0.0]
+    #[arg(short, long, env = "TPVBC2HTTP_BIND")]
+    bind: Option<String>,
+
+    /// Port on which to run the HTTP server [default: 8080]
+    #[arg(short, long, env = "TPVBC2HTTP_PORT")]
+    port: Option<u16>,
+
+    /// Threads handling connections, 0 for one per logical core [default: 0]
+    #[arg(long, env = "TPVBC2HTTP_THREADS")]
+    threads: Option<u32>,
+
+    /// Log filter, e.g. "debug" or "warn,tpvbc2http=debug" [default: info, or RUST_LOG]
+    #[arg(short, long, env = "TPVBC2HTTP_LOG")]
+    log: Option<String>,
 
     /// TrainingPeaks Virtual Broadcast directory
-    #[arg(short, long, default_value_t = String::new())]
-    tpvbcdir: String,
+    #[arg(short, long, env = "TPVBC2HTTP_TPVBCDIR")]
+    tpvbcdir: Option<String>,
+
+    /// Further broadcast directory as NAME=DIR, served below /bcast/NAME/ (repeatable)
+    #[arg(long, value_name = "NAME=DIR")]
+    source: Vec<String>,
 
     /// Static HTML directory
-    #[arg(short, long, default_value_t = String::new())]
-    statdir: String,
+    #[arg(short, long, env = "TPVBC2HTTP_STATDIR")]
+    statdir: Option<String>,
+
+    /// Seconds between full rescans of the broadcast directory, 0 to disable [default: 5]
+    #[arg(short, long, env = "TPVBC2HTTP_RESCAN")]
+    rescan: Option<u64>,
+
+    /// Milliseconds to wait for further changes of a broadcast file before reloading it [default: 50]
+    #[arg(long, env = "TPVBC2HTTP_DEBOUNCE_MS")]
+    debounce_ms: Option<u64>,
+
+    /// How to watch broadcast directories: native, polling (network shares) or auto [default: native]
+    #[arg(long, env = "TPVBC2HTTP_WATCHER")]
+    watcher: Option<String>,
+
+    /// Milliseconds between comparisons of a broadcast directory when polling [default: 1000]
+    #[arg(long, env = "TPVBC2HTTP_POLL_INTERVAL_MS")]
+    poll_interval_ms: Option<u64>,
+
+    /// Seconds without events before the auto watcher falls back to polling [default: 30]
+    #[arg(long, env = "TPVBC2HTTP_WATCHER_FALLBACK_SECS")]
+    watcher_fallback_secs: Option<u64>,
+
+    /// Seconds without TPV writing a document before it is stale, 0 for never [default: 10]
+    #[arg(long, env = "TPVBC2HTTP_STALE_SECS")]
+    stale_secs: Option<u64>,
+
+    /// What to serve for stale documents: last, empty or offline [default: last]
+    #[arg(long, env = "TPVBC2HTTP_STALE_PAYLOAD")]
+    stale_payload: Option<String>,
+
+    /// Functional threshold power of the rider in W, for IF and TSS
+    #[arg(long, env = "TPVBC2HTTP_FTP")]
+    ftp: Option<f64>,
+
+    /// Weight of the rider in kg, for W/kg
+    #[arg(long, env = "TPVBC2HTTP_WEIGHT")]
+    weight: Option<f64>,
+
+    /// Critical power of the rider in W for W'bal, defaults to the FTP
+    #[arg(long, env = "TPVBC2HTTP_CP")]
+    cp: Option<f64>,
+
+    /// W' of the rider in J, for W'bal
+    #[arg(long, env = "TPVBC2HTTP_W_PRIME")]
+    w_prime: Option<f64>,
+
+    /// Maximum heart rate of the rider in bpm, for heart rate zones
+    #[arg(long, env = "TPVBC2HTTP_MAX_HR")]
+    max_hr: Option<f64>,
+
+    /// TOML file mapping document names to broadcast files
+    #[arg(short, long, env = "TPVBC2HTTP_MAPPING")]
+    mapping: Option<String>,
+
+    /// Record every broadcast update to a journal in this directory, named sources in <dir>/<name>
+    #[arg(long, env = "TPVBC2HTTP_RECORD")]
+    record: Option<String>,
+
+    /// Size in MB after which the journal is rotated [default: 64]
+    #[arg(long)]
+    record_max_mb: Option<u64>,
+
+    /// Replay a recorded journal instead of watching the broadcast directory
+    #[arg(long, env = "TPVBC2HTTP_REPLAY")]
+    replay: Option<String>,
+
+    /// Replay speed factor [default: 1.0]
+    #[arg(long)]
+    replay_speed: Option<f64>,
+
+    /// Restart the replay when the end of the journal is reached
+    #[arg(long)]
+    replay_loop: bool,
 }
 
-mod server;
+impl Args {
+    /// Configuration file given explicitly, else the per-user one if it exists.
+    fn config(&self) -> Result<Config, String> {
+        match &self.config {
+            Some(file) => Config::load(Path::new(file)).map_err(|e| format!("{}: {}", file, e)),
+            None => match Config::default_path() {
+                Some(file) if file.is_file() => Config::load(&file).map_err(|e| format!("{}: {}", file.display(), e)),
+                _ => Ok(Config::default()),
+            },
+        }
+    }
 
-fn main() {
-    ctrlc::set_handler(move || {
-        log::info!("Shutdown!");
-        process::exit(0);
-    })
-    .expect("Error setting Ctrl-C handler");
+    /// Override values from the configuration file with the given flags (and environment variables).
+    fn apply(self, config: &mut Config) -> Result<(), String> {
+        if let Some(bind) = self.bind { config.bind = bind; }
+        if let Some(port) = self.port { config.port = port; }
+        if let Some(threads) = self.threads { config.threads = threads; }
+        if let Some(level) = self.log { config.log.level = level; }
+        if let Some(dir) = self.tpvbcdir { config.tpvbcdir = dir; }
+        if let Some(dir) = self.statdir { config.statdir = dir; }
+        if let Some(rescan) = self.rescan { config.rescan = rescan; }
+        if let Some(debounce_ms) = self.debounce_ms { config.debounce_ms = debounce_ms; }
+        if let Some(backend) = self.watcher { config.watcher.backend = backend; }
+        if let Some(interval) = self.poll_interval_ms { config.watcher.poll_interval_ms = interval; }
+        if let Some(secs) = self.watcher_fallback_secs { config.watcher.fallback_secs = secs; }
+        if let Some(secs) = self.stale_secs { config.stale.after_secs = secs; }
+        if let Some(payload) = self.stale_payload { config.stale.payload = payload; }
+        if let Some(ftp) = self.ftp { config.rider.ftp = ftp; }
+        if let Some(weight) = self.weight { config.rider.weight = weight; }
+        if let Some(cp) = self.cp { config.rider.cp = cp; }
+        if let Some(w_prime) = self.w_prime { config.rider.w_prime = w_prime; }
+        if let Some(max_hr) = self.max_hr { config.rider.max_hr = max_hr; }
+        if let Some(file) = self.mapping { config.mapping.file = file; }
+        if let Some(dir) = self.record { config.record.dir = dir; }
+        if let Some(max_mb) = self.record_max_mb { config.record.max_mb = max_mb; }
+        if let Some(journal) = self.replay { config.replay.journal = journal; }
+        if let Some(speed) = self.replay_speed { config.replay.speed = speed; }
+        if self.replay_loop { config.replay.looping = true; }
+
+        for source in self.source {
+            match source.split_once('=') {
+                Some((name, dir)) => config.sources.insert(name.to_string(), dir.to_string()),
+                None => return Err(format!("invalid source {}, expected NAME=DIR", source)),
+            };
+        }
+        Ok(())
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write synthetic broadcast files into a directory, like TPV does during an event
+    Simulate(simulator::Options),
+    /// Export a recorded journal: the ride as FIT, TCX or GPX file, or the session as CSV tables
+    Export(export::Options),
+}
 
-    colog::init();
-   
+mod export;
+mod simulator;
+
+fn main() {
     let path = env::current_dir().unwrap();
-    let args = Args::parse();
-    let mut tpvbcdir = format!("{}/http/testing/", path.display());
+    let mut args = Args::parse();
+
+    match args.command.take() {
+        Some(Command::Simulate(options)) => {
+            colog::init();
+            ctrlc::set_handler(move || {
+                log::info!("Shutdown!");
+                process::exit(0);
+            })
+            .expect("Error setting Ctrl-C handler");
+
+            if let Err(e) = simulator::run(options) {
+                log::error!("Simulation failed: {}", e);
+                process::exit(1);
+            }
+            return;
+        },
+        Some(Command::Export(options)) => {
+            colog::init();
+
+            if let Err(e) = export::run(options) {
+                log::error!("Export failed: {}", e);
+                process::exit(1);
+            }
+            return;
+        },
+        None => (),
+    }
 
-    if args.tpvbcdir.len() > 0 {
-        tpvbcdir = args.tpvbcdir;
+    let loaded = args.config();
+    let print_config = args.print_config;
+    let mut config = loaded.clone().unwrap_or_default();
+    if let Ok(level) = env::var("RUST_LOG") {
+        config.log.level = level;
     }
+    let applied = args.apply(&mut config);
 
-    info!("tpvbc2http\ncwd: {}\nport: {}\ntpvbcdir: {}", 
+    let mut logger = colog::basic_builder();
+    logger.parse_filters(&config.log.level);
+    logger.init();
+
+    if let Err(e) = loaded {
+        log::error!("Failed to read configuration {}", e);
+        process::exit(1);
+    }
+    if let Err(e) = applied {
+        log::error!("{}", e);
+        process::exit(1);
+    }
+    if print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
+
+    // with named sources only, there is no default one
+    if config.tpvbcdir.is_empty() && config.sources.is_empty() {
+        config.tpvbcdir = format!("{}/http/testing/", path.display());
+    }
+
+    info!("tpvbc2http\ncwd: {}\nbind: {}\nport: {}\ntpvbcdir: {}\nstatdir: {}\nrescan: {}s", 
         path.display(), 
-        args.port,
-        tpvbcdir,
+        config.bind,
+        config.port,
+        config.tpvbcdir,
+        config.statdir,
+        config.rescan,
     );
 
-    let s = server::Instance::new();
-    s.start("0.0.0.0", &format!("{}", args.port), tpvbcdir);
+    let mut s = server::Instance::new();
+    match config.mapping() {
+        Ok(mapping) => s.set_mapping(mapping),
+        Err(e) => {
+            log::error!("Failed to read {}", e);
+            process::exit(1);
+        },
+    }
+    for (name, dir) in &config.sources {
+        info!("source {}: {}", name, dir);
+        if let Err(e) = s.add_source(name, dir) {
+            log::error!("{}", e);
+            process::exit(1);
+        }
+    }
+    s.set_endpoints(config.endpoints);
+    s.set_rider(config.rider.clone());
+    s.set_shutdown_timeout(Some(Duration::from_secs(config.shutdown_timeout)));
+    if config.threads > 0 {
+        s.set_threads(Some(config.threads));
+    }
+    s.set_debounce(Duration::from_millis(config.debounce_ms));
+    match config.stale_payload() {
+        Ok(payload) => s.set_stale(config.stale_after(), payload),
+        Err(e) => {
+            log::error!("{}", e);
+            process::exit(1);
+        },
+    }
+    match config.watcher() {
+        Ok(backend) => s.set_watcher(backend),
+        Err(e) => {
+            log::error!("{}", e);
+            process::exit(1);
+        },
+    }
+    if config.rescan > 0 {
+        s.set_rescan_interval(Some(Duration::from_secs(config.rescan)));
+    }
+    if !config.statdir.is_empty() {
+        s.set_static_dir(&config.statdir);
+    }
+    if !config.record.dir.is_empty() {
+        s.set_record_dir(&config.record.dir, config.record.max_mb * 1024 * 1024);
+    }
+    if !config.replay.journal.is_empty() {
+        if !(replay::MIN_SPEED..=replay::MAX_SPEED).contains(&config.replay.speed) {
+            log::error!("Replay speed must be from {} to {}", replay::MIN_SPEED, replay::MAX_SPEED);
+            process::exit(1);
+        }
+
+        match recorder::read_journal(Path::new(&config.replay.journal)) {
+            Ok(records) => s.set_replay(records, config.replay.speed, config.replay.looping),
+            Err(e) => {
+                log::error!("Failed to read journal {}: {}", config.replay.journal, e);
+                process::exit(1);
+            },
+        }
+    }
+
+    let handle = match s.start(&config.bind, &format!("{}", config.port), Some(config.tpvbcdir.clone()).filter(|dir| !dir.is_empty())) {
+        Ok(handle) => handle,
+        Err(e) => {
+            log::error!("Failed to start: {}", e);
+            process::exit(1);
+        },
+    };
+
+    // first SIGINT/SIGTERM stops gracefully, the second one right away
+    let server = handle.clone();
+    let stopping = AtomicBool::new(false);
+    ctrlc::set_handler(move || {
+        if stopping.swap(true, Ordering::SeqCst) {
+            log::warn!("Forced shutdown!");
+            process::exit(1);
+        }
+        log::info!("Shutdown!");
+        server.shutdown();
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    handle.wait();
 }
@@ -0,0 +1,157 @@
+//! Which broadcast files are exposed under which document name.
+//!
+//! The mapping file is TOML, every entry in `[documents]` maps a document
+//! name (served as `/bcast/<name>`) to a file in the broadcast directory:
+//!
+//! ```toml
+//! # expose every other *.json file under its file stem (default)
+//! auto = true
+//!
+//! [documents]
+//! # TPV renamed a file
+//! focus = "rider.json"
+//! # drop a document
+//! entries = ""
+//! ```
+//!
+//! Entries are merged into the built-in mapping of the TPV documents. A
+//! dropped document stays dropped, `auto` does not expose its file again.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use serde::Deserialize;
+
+/// Names used by other routes below /bcast/ and by the derived documents.
+pub const RESERVED: [&str; 7] = ["status", "watcher", "stream", "ws", "metrics", "wbal", "zones"];
+
+/// Broadcast documents written by TPV, each one as <name>.json.
+pub const TPV_DOCUMENTS: [&str; 7] = [
+    "focus",
+    "nearest",
+    "entries",
+    "event",
+    "groups",
+    "resultsIndv",
+    "resultsTeam",
+];
+
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    /// Expose every *.json file not mapped explicitly under its file stem.
+    pub auto: bool,
+    /// Document name to file name.
+    pub documents: BTreeMap<String, String>,
+    /// Document names removed with an empty file name, never exposed by `auto`.
+    pub removed: BTreeSet<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MappingFile {
+    auto: Option<bool>,
+    #[serde(default)]
+    documents: BTreeMap<String, String>,
+}
+
+impl Default for Mapping {
+    fn default() -> Mapping {
+        Mapping {
+            auto: true,
+            documents: TPV_DOCUMENTS.iter().map(|name| (name.to_string(), format!("{}.json", name))).collect(),
+            removed: BTreeSet::new(),
+        }
+    }
+}
+
+impl Mapping {
+    /// Load a mapping file and merge it into this mapping.
+    pub fn merge_file(self, path: &Path) -> Result<Mapping, String> {
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        self.merge_toml(&content)
+    }
+
+    pub fn merge_toml(self, content: &str) -> Result<Mapping, String> {
+        let file: MappingFile = toml::from_str(content).map_err(|e| e.to_string())?;
+        self.merge(file.auto, file.documents)
+    }
+
+    /// Merge entries into this mapping, an empty file name removes a document.
+    pub fn merge(mut self, auto: Option<bool>, documents: BTreeMap<String, String>) -> Result<Mapping, String> {
+        if let Some(auto) = auto {
+            self.auto = auto;
+        }
+
+        for (name, file) in documents {
+            if !Mapping::valid_name(&name) {
+                return Err(format!("invalid document name \"{}\"", name));
+            }
+            if file.is_empty() {
+                self.documents.remove(&name);
+                self.removed.insert(name);
+            } else {
+                self.removed.remove(&name);
+                self.documents.insert(name, file);
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Names must be usable as a single path segment and not hide another route.
+    pub fn valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && !RESERVED.contains(&name)
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    }
+
+    /// Document name for a file in the broadcast directory.
+    pub fn name_for(&self, file_name: &str) -> Option<String> {
+        if let Some((name, _)) = self.documents.iter().find(|(_, file)| *file == file_name) {
+            return Some(name.clone());
+        }
+
+        let stem = file_name.strip_suffix(".json")?;
+        if self.auto && Mapping::valid_name(stem) && !self.documents.contains_key(stem) && !self.removed.contains(stem) {
+            Some(stem.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// File name of a document.
+    pub fn file_for(&self, name: &str) -> String {
+        self.documents.get(name).cloned().unwrap_or_else(|| format!("{}.json", name))
+    }
+}
+
+#[cfg(test)]
+mod mapping_should {
+    use super::*;
+
+    #[test]
+    fn merge_file_into_defaults() {
+        let mapping = Mapping::default().merge_toml("[documents]\nfocus = \"rider.json\"\nentries = \"\"\n").unwrap();
+
+        assert_eq!(Some(String::from("focus")), mapping.name_for("rider.json"));
+        assert_eq!(Some(String::from("nearest")), mapping.name_for("nearest.json"));
+        assert_eq!(None, mapping.name_for("focus.json"));
+        assert_eq!(None, mapping.name_for("entries.json"));
+        assert!(!mapping.documents.contains_key("entries"));
+    }
+
+    #[test]
+    fn expose_other_files_only_with_auto() {
+        assert_eq!(Some(String::from("laps")), Mapping::default().name_for("laps.json"));
+        assert_eq!(None, Mapping::default().name_for("status.json"));
+        assert_eq!(None, Mapping::default().name_for("notes.txt"));
+        assert_eq!(None, Mapping::default().merge_toml("auto = false").unwrap().name_for("laps.json"));
+    }
+
+    #[test]
+    fn reject_invalid_names() {
+        assert!(Mapping::default().merge_toml("[documents]\nstream = \"x.json\"\n").is_err());
+        assert!(Mapping::default().merge_toml("[documents]\n\"a/b\" = \"x.json\"\n").is_err());
+        assert!(Mapping::default().merge_toml("unknown = 1").is_err());
+    }
+}
@@ -0,0 +1,488 @@
+//! Metrics derived from the history of the focus document.
+//!
+//! TPV only fills `avgPower`, `nrmPower` and `tss` during events, so we keep
+//! our own history of the rider in focus. Samples are resampled to one value
+//! per second of ride time (the focus `time`), holding the last value for
+//! seconds without a sample.
+//!
+//! Besides the metrics document, W'bal (Skiba's differential model) and the
+//! time in power and heart rate zones are tracked for the rider profile.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use serde::Serialize;
+use crate::cache::{Cache, Subscription};
+use crate::config::Rider;
+use crate::model::Focus;
+
+/// Names of the derived documents.
+pub const METRICS: &str = "metrics";
+pub const WBAL: &str = "wbal";
+pub const ZONES: &str = "zones";
+/// All derived documents, computed again from focus instead of being recorded.
+pub const DERIVED: [&str; 3] = [METRICS, WBAL, ZONES];
+
+/// Gaps in the ride time longer than this are counted as a single second,
+/// e.g. when TPV was paused or the rider switched rides.
+const MAX_GAP_SECS: u64 = 10;
+
+/// Window of the rolling average used for normalized power.
+const NP_WINDOW_SECS: usize = 30;
+
+/// The metrics document, a single record.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsRecord {
+    pub name: String,
+    /// Ride time in seconds, as in the focus document.
+    pub time: f64,
+    /// Seconds of history the metrics are based on.
+    pub duration: u64,
+    pub power: f64,
+    pub power3s: f64,
+    pub power10s: f64,
+    pub power30s: f64,
+    pub avg_power: f64,
+    pub max_power: f64,
+    pub nrm_power: f64,
+    /// Energy in kJ.
+    pub work: f64,
+    /// `None` without a configured FTP.
+    pub intensity_factor: Option<f64>,
+    pub tss: Option<f64>,
+    pub ftp: Option<f64>,
+    /// `None` without a configured weight.
+    pub watts_per_kg: Option<f64>,
+    /// Average of the seconds with cadence/heart rate, zeros are left out.
+    pub avg_cadence: f64,
+    pub avg_heartrate: f64,
+}
+
+/// The W'bal document, a single record. Energies in J, `None` without CP and W'.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WBalRecord {
+    pub name: String,
+    pub time: f64,
+    pub cp: Option<f64>,
+    pub w_prime: Option<f64>,
+    pub wbal: Option<f64>,
+    pub wbal_percent: Option<f64>,
+    /// Lowest W'bal of the session.
+    pub min_wbal: Option<f64>,
+    /// Work done above CP during the session.
+    pub expended: Option<f64>,
+    pub seconds_above_cp: u64,
+}
+
+/// The zones document, a single record.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ZonesRecord {
+    pub name: String,
+    pub time: f64,
+    /// Current zone, `None` without FTP / maximum heart rate.
+    pub power_zone: Option<usize>,
+    pub heartrate_zone: Option<usize>,
+    pub power: Vec<ZoneTime>,
+    pub heartrate: Vec<ZoneTime>,
+}
+
+/// Time spent in a zone during the session.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ZoneTime {
+    /// Starting at 1.
+    pub zone: usize,
+    pub from: f64,
+    /// `None` for the last, open zone.
+    pub to: Option<f64>,
+    pub seconds: u64,
+}
+
+/// Rolling history of the rider in focus.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    rider: Rider,
+    name: String,
+    last_time: Option<f64>,
+    power: f64,
+    cadence: f64,
+    heartrate: f64,
+    // last seconds of power, enough for the longest window
+    window: VecDeque<f64>,
+    seconds: u64,
+    power_sum: f64,
+    max_power: f64,
+    np_sum: f64,
+    np_count: u64,
+    cadence_sum: f64,
+    cadence_count: u64,
+    heartrate_sum: f64,
+    heartrate_count: u64,
+    wbal: Option<f64>,
+    min_wbal: Option<f64>,
+    expended: f64,
+    seconds_above_cp: u64,
+    power_zone_seconds: Vec<u64>,
+    heartrate_zone_seconds: Vec<u64>,
+}
+
+impl Metrics {
+    pub fn new(rider: Rider) -> Metrics {
+        let power_zone_seconds = vec![0; rider.power_zones.len() + 1];
+        let heartrate_zone_seconds = vec![0; rider.hr_zones.len() + 1];
+        Metrics { rider, power_zone_seconds, heartrate_zone_seconds, ..Metrics::default() }
+    }
+
+    /// Add a focus sample, a new rider or a ride time going backwards starts over.
+    pub fn add(&mut self, focus: &Focus) {
+        if focus.name != self.name || self.last_time.is_some_and(|t| focus.time < t) {
+            *self = Metrics::new(self.rider.clone());
+            self.name = focus.name.clone();
+        }
+
+        let elapsed = match self.last_time {
+            Some(t) => (focus.time.floor() - t.floor()).max(0.0) as u64,
+            None => 1,
+        };
+        let elapsed = if elapsed > MAX_GAP_SECS { 1 } else { elapsed };
+
+        // the seconds without a sample hold the last values
+        for _ in 1..elapsed {
+            self.second();
+        }
+
+        self.power = focus.power;
+        self.cadence = focus.cadence;
+        self.heartrate = focus.heartrate;
+        if elapsed > 0 {
+            self.second();
+        }
+        self.last_time = Some(focus.time);
+    }
+
+    // Account one second with the current values.
+    fn second(&mut self) {
+        self.window.push_back(self.power);
+        if self.window.len() > NP_WINDOW_SECS {
+            self.window.pop_front();
+        }
+
+        self.seconds += 1;
+        self.power_sum += self.power;
+        self.max_power = self.max_power.max(self.power);
+
+        if self.window.len() == NP_WINDOW_SECS {
+            self.np_sum += self.rolling(NP_WINDOW_SECS).powi(4);
+            self.np_count += 1;
+        }
+        if self.cadence > 0.0 {
+            self.cadence_sum += self.cadence;
+            self.cadence_count += 1;
+        }
+        if self.heartrate > 0.0 {
+            self.heartrate_sum += self.heartrate;
+            self.heartrate_count += 1;
+        }
+
+        if let Some((cp, w_prime)) = self.w_prime() {
+            // Skiba differential model: deplete above CP, recover below it
+            // the closer to full, the slower
+            let wbal = self.wbal.unwrap_or(w_prime);
+            let wbal = if self.power > cp {
+                self.expended += self.power - cp;
+                self.seconds_above_cp += 1;
+                wbal - (self.power - cp)
+            } else {
+                wbal + (w_prime - wbal) * (cp - self.power) / w_prime
+            };
+            self.wbal = Some(wbal);
+            self.min_wbal = Some(self.min_wbal.map_or(wbal, |min| min.min(wbal)));
+        }
+
+        if let Some(zone) = self.power_zone() {
+            self.power_zone_seconds[zone] += 1;
+        }
+        if let Some(zone) = self.heartrate_zone() {
+            self.heartrate_zone_seconds[zone] += 1;
+        }
+    }
+
+    /// CP and W' if W'bal is tracked.
+    fn w_prime(&self) -> Option<(f64, f64)> {
+        let cp = if self.rider.cp > 0.0 { self.rider.cp } else { self.rider.ftp };
+        Some((cp, self.rider.w_prime)).filter(|(cp, w_prime)| *cp > 0.0 && *w_prime > 0.0)
+    }
+
+    /// Zone bounds in W / bpm.
+    fn bounds(percent: &[f64], reference: f64) -> Vec<f64> {
+        percent.iter().map(|p| round(p * reference / 100.0, 0)).collect()
+    }
+
+    fn power_zone(&self) -> Option<usize> {
+        Some(self.rider.ftp).filter(|ftp| *ftp > 0.0)
+            .map(|ftp| zone(self.power, &Metrics::bounds(&self.rider.power_zones, ftp)))
+    }
+
+    fn heartrate_zone(&self) -> Option<usize> {
+        Some(self.rider.max_hr).filter(|max_hr| *max_hr > 0.0 && self.heartrate > 0.0)
+            .map(|max_hr| zone(self.heartrate, &Metrics::bounds(&self.rider.hr_zones, max_hr)))
+    }
+
+    /// Average power of the last `secs` seconds (or less at the start).
+    fn rolling(&self, secs: usize) -> f64 {
+        let n = self.window.len().min(secs);
+        if n == 0 {
+            return 0.0;
+        }
+        self.window.iter().rev().take(n).sum::<f64>() / n as f64
+    }
+
+    pub fn avg_power(&self) -> f64 {
+        average(self.power_sum, self.seconds)
+    }
+
+    /// Normalized power, the average power until there are 30 seconds of history.
+    pub fn nrm_power(&self) -> f64 {
+        if self.np_count == 0 {
+            self.avg_power()
+        } else {
+            (self.np_sum / self.np_count as f64).powf(0.25)
+        }
+    }
+
+    pub fn record(&self) -> MetricsRecord {
+        let nrm_power = self.nrm_power();
+        let ftp = Some(self.rider.ftp).filter(|ftp| *ftp > 0.0);
+        let intensity_factor = ftp.map(|ftp| nrm_power / ftp);
+        let tss = ftp.zip(intensity_factor)
+            .map(|(ftp, intensity)| self.seconds as f64 * nrm_power * intensity / (ftp * 3600.0) * 100.0);
+        let weight = Some(self.rider.weight).filter(|weight| *weight > 0.0);
+
+        MetricsRecord {
+            name: self.name.clone(),
+            time: self.last_time.unwrap_or_default(),
+            duration: self.seconds,
+            power: self.power,
+            power3s: round(self.rolling(3), 1),
+            power10s: round(self.rolling(10), 1),
+            power30s: round(self.rolling(30), 1),
+            avg_power: round(self.avg_power(), 1),
+            max_power: self.max_power,
+            nrm_power: round(nrm_power, 1),
+            work: round(self.power_sum / 1000.0, 1),
+            intensity_factor: intensity_factor.map(|v| round(v, 3)),
+            tss: tss.map(|v| round(v, 1)),
+            ftp,
+            watts_per_kg: weight.map(|weight| round(self.power / weight, 2)),
+            avg_cadence: round(average(self.cadence_sum, self.cadence_count), 1),
+            avg_heartrate: round(average(self.heartrate_sum, self.heartrate_count), 1),
+        }
+    }
+
+    pub fn wbal_record(&self) -> WBalRecord {
+        let w_prime = self.w_prime();
+
+        WBalRecord {
+            name: self.name.clone(),
+            time: self.last_time.unwrap_or_default(),
+            cp: w_prime.map(|(cp, _)| cp),
+            w_prime: w_prime.map(|(_, w_prime)| w_prime),
+            wbal: w_prime.map(|(_, w_prime)| round(self.wbal.unwrap_or(w_prime), 0)),
+            wbal_percent: w_prime.map(|(_, w_prime)| round(self.wbal.unwrap_or(w_prime) / w_prime * 100.0, 1)),
+            min_wbal: w_prime.map(|(_, w_prime)| round(self.min_wbal.unwrap_or(w_prime), 0)),
+            expended: w_prime.map(|_| round(self.expended, 0)),
+            seconds_above_cp: self.seconds_above_cp,
+        }
+    }
+
+    pub fn zones_record(&self) -> ZonesRecord {
+        let power = match self.rider.ftp {
+            ftp if ftp > 0.0 => zone_times(&Metrics::bounds(&self.rider.power_zones, ftp), &self.power_zone_seconds),
+            _ => Vec::new(),
+        };
+        let heartrate = match self.rider.max_hr {
+            max_hr if max_hr > 0.0 => zone_times(&Metrics::bounds(&self.rider.hr_zones, max_hr), &self.heartrate_zone_seconds),
+            _ => Vec::new(),
+        };
+
+        ZonesRecord {
+            name: self.name.clone(),
+            time: self.last_time.unwrap_or_default(),
+            power_zone: self.power_zone().map(|zone| zone + 1),
+            heartrate_zone: self.heartrate_zone().map(|zone| zone + 1),
+            power,
+            heartrate,
+        }
+    }
+}
+
+/// Index of the zone `value` is in, given the upper bounds of the zones.
+fn zone(value: f64, bounds: &[f64]) -> usize {
+    bounds.iter().position(|bound| value < *bound).unwrap_or(bounds.len())
+}
+
+fn zone_times(bounds: &[f64], seconds: &[u64]) -> Vec<ZoneTime> {
+    seconds.iter().enumerate()
+        .map(|(i, seconds)| ZoneTime {
+            zone: i + 1,
+            from: if i == 0 { 0.0 } else { bounds[i - 1] },
+            to: bounds.get(i).copied(),
+            seconds: *seconds,
+        })
+        .collect()
+}
+
+fn average(sum: f64, count: u64) -> f64 {
+    if count == 0 { 0.0 } else { sum / count as f64 }
+}
+
+fn round(value: f64, digits: i32) -> f64 {
+    let factor = 10f64.powi(digits);
+    (value * factor).round() / factor
+}
+
+/// Keep the metrics, W'bal and zones documents of `cache` up to date with its focus document.
+pub fn start(cache: &Cache, rider: Rider) -> Subscription {
+    let metrics = Arc::new(Mutex::new(Metrics::new(rider)));
+    let target = cache.clone();
+
+    // listed from the start, empty until there is a focus document
+    for name in DERIVED {
+        cache.update(name, String::from("[]"));
+    }
+
+    cache.on_update(move |update| {
+        let focus = match update.document.as_ref().and_then(|document| document.focus()) {
+            Some(focus) => focus,
+            None => return,
+        };
+
+        let (record, wbal, zones) = {
+            let mut metrics_locked = metrics.lock().unwrap();
+            metrics_locked.add(focus);
+            (metrics_locked.record(), metrics_locked.wbal_record(), metrics_locked.zones_record())
+        };
+
+        target.update(METRICS, serde_json::to_string(&[record]).unwrap());
+        target.update(WBAL, serde_json::to_string(&[wbal]).unwrap());
+        target.update(ZONES, serde_json::to_string(&[zones]).unwrap());
+    })
+}
+
+#[cfg(test)]
+mod metrics_should {
+    use super::*;
+
+    fn focus(time: f64, power: f64) -> Focus {
+        Focus { name: String::from("Joe"), time, power, cadence: 90.0, heartrate: 0.0, ..Focus::default() }
+    }
+
+    #[test]
+    fn compute_steady_ride() {
+        let mut metrics = Metrics::new(Rider { ftp: 250.0, weight: 80.0, ..Rider::default() });
+        for t in 0..3600 {
+            metrics.add(&focus(t as f64, 200.0));
+        }
+
+        let record = metrics.record();
+        assert_eq!(3600, record.duration);
+        assert_eq!(200.0, record.power30s);
+        assert_eq!(200.0, record.nrm_power);
+        assert_eq!(Some(0.8), record.intensity_factor);
+        assert_eq!(Some(64.0), record.tss);
+        assert_eq!(720.0, record.work);
+        assert_eq!(Some(2.5), record.watts_per_kg);
+        assert_eq!(90.0, record.avg_cadence);
+        assert_eq!(0.0, record.avg_heartrate);
+    }
+
+    #[test]
+    fn hold_values_between_samples_and_start_over_for_a_new_ride() {
+        let mut metrics = Metrics::new(Rider::default());
+        metrics.add(&focus(100.0, 100.0));
+        metrics.add(&focus(103.0, 400.0));
+
+        let record = metrics.record();
+        assert_eq!(4, record.duration);
+        assert_eq!(200.0, record.power3s);
+        assert_eq!(175.0, record.avg_power);
+        assert_eq!(None, record.tss);
+
+        metrics.add(&focus(5.0, 150.0));
+        assert_eq!(1, metrics.record().duration);
+    }
+
+    #[test]
+    fn fill_a_gap_with_the_previous_sample() {
+        let mut metrics = Metrics::new(Rider { ftp: 200.0, ..Rider::default() });
+        for t in 0..30 {
+            metrics.add(&focus(t as f64, 100.0));
+        }
+        metrics.add(&focus(37.0, 300.0));
+
+        let record = metrics.record();
+        assert_eq!(38, record.duration);
+        assert_eq!(300.0, record.max_power);
+        assert_eq!(round((37.0 * 100.0 + 300.0) / 38.0, 1), record.avg_power);
+        assert_eq!(round((29.0 * 100.0 + 300.0) / 30.0, 1), record.power30s);
+
+        let zones = metrics.zones_record();
+        assert_eq!(37, zones.power[0].seconds);
+        assert_eq!(1, zones.power[6].seconds);
+    }
+
+    #[test]
+    fn weigh_surges_in_normalized_power() {
+        let mut metrics = Metrics::new(Rider::default());
+        for t in 0..600 {
+            metrics.add(&focus(t as f64, if (t / 60) % 2 == 0 { 100.0 } else { 300.0 }));
+        }
+
+        let record = metrics.record();
+        assert_eq!(200.0, record.avg_power);
+        assert!(record.nrm_power > 220.0);
+    }
+
+    #[test]
+    fn deplete_and_recover_wbal() {
+        let mut metrics = Metrics::new(Rider { cp: 250.0, w_prime: 20000.0, ..Rider::default() });
+        for t in 0..60 {
+            metrics.add(&focus(t as f64, 450.0));
+        }
+
+        let wbal = metrics.wbal_record();
+        assert_eq!(Some(8000.0), wbal.wbal);
+        assert_eq!(Some(12000.0), wbal.expended);
+        assert_eq!(60, wbal.seconds_above_cp);
+
+        for t in 60..360 {
+            metrics.add(&focus(t as f64, 100.0));
+        }
+
+        let wbal = metrics.wbal_record();
+        assert!(wbal.wbal.unwrap() > 18000.0 && wbal.wbal.unwrap() < 20000.0);
+        assert_eq!(Some(8000.0), wbal.min_wbal);
+        assert_eq!(None, Metrics::new(Rider::default()).wbal_record().wbal);
+    }
+
+    #[test]
+    fn count_time_in_zones() {
+        let mut metrics = Metrics::new(Rider { ftp: 200.0, max_hr: 200.0, ..Rider::default() });
+        for t in 0..10 {
+            let mut sample = focus(t as f64, if t < 4 { 100.0 } else { 320.0 });
+            sample.heartrate = 150.0;
+            metrics.add(&sample);
+        }
+
+        let zones = metrics.zones_record();
+        assert_eq!(Some(7), zones.power_zone);
+        assert_eq!(4, zones.power[0].seconds);
+        assert_eq!(6, zones.power[6].seconds);
+        assert_eq!(None, zones.power[6].to);
+        assert_eq!(Some(3), zones.heartrate_zone);
+        assert_eq!(10, zones.heartrate[2].seconds);
+        assert_eq!(Some(160.0), zones.heartrate[2].to);
+    }
+}
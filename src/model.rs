@@ -0,0 +1,210 @@
+//! Typed view on the broadcast documents written by TPV.
+//!
+//! Every document is a JSON array of records. Fields missing in a record get
+//! their default value, fields we do not know (yet) end up in `extra`, so
+//! newer TPV versions do not break parsing and nothing is lost when a record
+//! is serialized again.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Rider in focus (focus.json), speed in mm/s.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Focus {
+    pub name: String,
+    pub country: String,
+    pub team: String,
+    pub team_code: String,
+    pub power: f64,
+    pub avg_power: f64,
+    pub nrm_power: f64,
+    pub max_power: f64,
+    pub cadence: f64,
+    pub avg_cadence: f64,
+    pub max_cadence: f64,
+    pub heartrate: f64,
+    pub avg_heartrate: f64,
+    pub max_heartrate: f64,
+    pub time: f64,
+    pub distance: f64,
+    pub height: f64,
+    pub speed: f64,
+    pub tss: f64,
+    pub calories: f64,
+    pub draft: f64,
+    pub wind_speed: f64,
+    pub wind_angle: f64,
+    pub slope: f64,
+    pub event_laps_total: i64,
+    pub event_laps_done: i64,
+    pub event_distance_total: f64,
+    pub event_distance_done: f64,
+    pub event_distance_to_next_location: f64,
+    pub event_next_location: i64,
+    pub event_position: i64,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Rider close to the one in focus (nearest.json), time gap in ms.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Nearest {
+    pub name: String,
+    pub country: String,
+    pub team: String,
+    pub team_code: String,
+    pub speed: f64,
+    pub time_gap: f64,
+    pub position: i64,
+    pub distance: f64,
+    pub is_eliminated: bool,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Rider registered for the event (entries.json).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Entry {
+    pub bib_num: i64,
+    pub name: String,
+    pub country: String,
+    pub team: String,
+    pub team_code: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The event itself (event.json).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Event {
+    pub name: String,
+    pub route: String,
+    pub laps: i64,
+    pub distance: f64,
+    pub height: f64,
+    pub locations: i64,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Group on the road (groups.json), time gaps in ms.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Group {
+    pub group_num1: i64,
+    pub group_num2: i64,
+    pub leader: Option<String>,
+    pub size: i64,
+    pub time_gap1: f64,
+    pub time_gap2: f64,
+    pub is_peloton: bool,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Individual result at a location (resultsIndv.json), times in ms.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ResultIndv {
+    pub location: i64,
+    pub position: i64,
+    pub name: String,
+    pub country: String,
+    pub team: String,
+    pub team_code: String,
+    pub points: i64,
+    pub points_total: i64,
+    pub time: f64,
+    pub delta_time: f64,
+    pub is_eliminated: bool,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Team result at a location (resultsTeam.json), times in ms.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ResultTeam {
+    pub location: i64,
+    pub position: i64,
+    pub team: String,
+    pub team_code: String,
+    pub points_total: i64,
+    pub time: f64,
+    pub delta_time: f64,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// A parsed broadcast document.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Document {
+    Focus(Vec<Focus>),
+    Nearest(Vec<Nearest>),
+    Entries(Vec<Entry>),
+    Event(Vec<Event>),
+    Groups(Vec<Group>),
+    ResultsIndv(Vec<ResultIndv>),
+    ResultsTeam(Vec<ResultTeam>),
+}
+
+impl Document {
+    /// Parse the content of a broadcast document, `None` if the name is not known.
+    pub fn parse(name: &str, raw: &str) -> Option<serde_json::Result<Document>> {
+        let document = match name {
+            "focus"       => serde_json::from_str(raw).map(Document::Focus),
+            "nearest"     => serde_json::from_str(raw).map(Document::Nearest),
+            "entries"     => serde_json::from_str(raw).map(Document::Entries),
+            "event"       => serde_json::from_str(raw).map(Document::Event),
+            "groups"      => serde_json::from_str(raw).map(Document::Groups),
+            "resultsIndv" => serde_json::from_str(raw).map(Document::ResultsIndv),
+            "resultsTeam" => serde_json::from_str(raw).map(Document::ResultsTeam),
+            _ => return None,
+        };
+        Some(document)
+    }
+
+    /// The rider in focus, if this is a focus document.
+    pub fn focus(&self) -> Option<&Focus> {
+        match self {
+            Document::Focus(focus) => focus.first(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod model_should {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn parse_testing_documents() {
+        for name in ["focus", "nearest", "entries", "event", "groups", "resultsIndv", "resultsTeam"] {
+            let raw = fs::read_to_string(format!("http/testing/{}.json", name)).unwrap();
+            let raw = raw.trim_start_matches('\u{feff}');
+            let document = Document::parse(name, raw).unwrap();
+            assert!(document.is_ok(), "{}: {:?}", name, document);
+        }
+    }
+
+    #[test]
+    fn preserve_unknown_fields() {
+        let raw = r#"[{"name":"Joe","power":250,"newField":{"a":1}}]"#;
+        let document = Document::parse("focus", raw).unwrap().unwrap();
+        let focus = document.focus().unwrap();
+
+        assert_eq!(250.0, focus.power);
+        assert_eq!(Some(&serde_json::json!({"a":1})), focus.extra.get("newField"));
+
+        let again = serde_json::to_value(&document).unwrap();
+        assert_eq!(serde_json::json!({"a":1}), again[0]["newField"]);
+    }
+}
@@ -0,0 +1,128 @@
+//! `/metrics` in the Prometheus text exposition format.
+//!
+//! Rider and race gauges come from the current focus document of every
+//! source, the default directory has an empty `source` label. Counters are
+//! totals since the start of the server.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use simple_server::{RouteStats, LATENCY_BUCKETS};
+use crate::cache::Cache;
+use crate::model::Focus;
+
+/// A gauge taken from the focus document: name, help, value.
+type FocusGauge = (&'static str, &'static str, fn(&Focus) -> f64);
+
+const FOCUS_GAUGES: [FocusGauge; 11] = [
+    ("tpv_rider_power_watts", "Current power of the rider in focus.", |f| f.power),
+    ("tpv_rider_cadence_rpm", "Current cadence of the rider in focus.", |f| f.cadence),
+    ("tpv_rider_heartrate_bpm", "Current heart rate of the rider in focus.", |f| f.heartrate),
+    ("tpv_rider_speed_meters_per_second", "Current speed of the rider in focus.", |f| f.speed / 1000.0),
+    ("tpv_rider_distance_meters", "Distance ridden by the rider in focus.", |f| f.distance),
+    ("tpv_rider_draft", "Current draft of the rider in focus, as reported by TPV.", |f| f.draft),
+    ("tpv_race_position", "Position of the rider in focus in the event.", |f| f.event_position as f64),
+    ("tpv_race_laps_done", "Laps done by the rider in focus.", |f| f.event_laps_done as f64),
+    ("tpv_race_laps_total", "Laps of the event.", |f| f.event_laps_total as f64),
+    ("tpv_race_distance_to_next_location_meters", "Distance of the rider in focus to the next location.", |f| f.event_distance_to_next_location),
+    ("tpv_race_next_location", "Next location of the rider in focus.", |f| f.event_next_location as f64),
+];
+
+/// Everything in the text format, `caches` by source name.
+pub fn render(caches: &[(&str, &Cache)], requests: &BTreeMap<String, RouteStats>) -> String {
+    let mut out = String::new();
+
+    let focus: Vec<(&str, Focus)> = caches.iter()
+        .filter_map(|(source, cache)| Some((*source, cache.document("focus")?.focus()?.clone())))
+        .collect();
+
+    header(&mut out, "tpv_rider_info", "gauge", "Rider in focus, always 1.");
+    for (source, focus) in &focus {
+        let _ = writeln!(out, "tpv_rider_info{{source=\"{}\",name=\"{}\",team=\"{}\"}} 1",
+            escape(source), escape(&focus.name), escape(&focus.team));
+    }
+    for (name, help, value) in FOCUS_GAUGES {
+        header(&mut out, name, "gauge", help);
+        for (source, focus) in &focus {
+            let _ = writeln!(out, "{}{{source=\"{}\"}} {}", name, escape(source), value(focus));
+        }
+    }
+
+    let counters = [
+        ("tpv_document_updates_total", "Changes of a document."),
+        ("tpv_document_writes_total", "Writes of a document, changed or not."),
+        ("tpv_document_parse_failures_total", "Document content which could not be parsed."),
+        ("tpv_document_reload_failures_total", "Reloads of a document given up after retrying."),
+    ];
+    for (i, (name, help)) in counters.iter().enumerate() {
+        header(&mut out, name, "counter", help);
+        for (source, cache) in caches {
+            for document in cache.counters() {
+                let value = [document.updates, document.writes, document.parse_failures, document.reload_failures][i];
+                let _ = writeln!(out, "{}{{source=\"{}\",document=\"{}\"}} {}", name, escape(source), escape(&document.name), value);
+            }
+        }
+    }
+
+    header(&mut out, "tpv_watcher_errors_total", "counter", "Errors reported by the directory watcher.");
+    for (source, cache) in caches {
+        let _ = writeln!(out, "tpv_watcher_errors_total{{source=\"{}\"}} {}", escape(source), cache.watch_errors());
+    }
+
+    header(&mut out, "http_requests_total", "counter", "HTTP requests by route and status.");
+    for (route, stats) in requests {
+        for (status, count) in &stats.requests {
+            let _ = writeln!(out, "http_requests_total{{route=\"{}\",status=\"{}\"}} {}", escape(route), status, count);
+        }
+    }
+
+    header(&mut out, "http_request_duration_seconds", "histogram", "Time to answer HTTP requests by route.");
+    for (route, stats) in requests {
+        let route = escape(route);
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&stats.latencies) {
+            cumulative += count;
+            let _ = writeln!(out, "http_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}", route, bound, cumulative);
+        }
+        let _ = writeln!(out, "http_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}", route, stats.count());
+        let _ = writeln!(out, "http_request_duration_seconds_sum{{route=\"{}\"}} {}", route, stats.latency_sum.as_secs_f64());
+        let _ = writeln!(out, "http_request_duration_seconds_count{{route=\"{}\"}} {}", route, stats.count());
+    }
+
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+/// Label values escape backslash, double quote and line feed.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod prometheus_should {
+    use super::*;
+    use std::time::Duration;
+    use simple_server::{Stats, StatusCode};
+
+    #[test]
+    fn expose_focus_documents_and_requests() {
+        let cache = Cache::new();
+        cache.update("focus", String::from(r#"[{"name":"Joe \"J\"","power":250,"speed":10000,"eventPosition":3}]"#));
+
+        let stats = Stats::default();
+        stats.record("/bcast/{doc}", StatusCode::OK, Duration::from_millis(3));
+        stats.record("/bcast/{doc}", StatusCode::NOT_FOUND, Duration::from_secs(2));
+
+        let text = render(&[("", &cache)], &stats.snapshot());
+        assert!(text.contains("tpv_rider_info{source=\"\",name=\"Joe \\\"J\\\"\",team=\"\"} 1\n"));
+        assert!(text.contains("tpv_rider_power_watts{source=\"\"} 250\n"));
+        assert!(text.contains("tpv_rider_speed_meters_per_second{source=\"\"} 10\n"));
+        assert!(text.contains("tpv_race_position{source=\"\"} 3\n"));
+        assert!(text.contains("tpv_document_updates_total{source=\"\",document=\"focus\"} 1\n"));
+        assert!(text.contains("http_requests_total{route=\"/bcast/{doc}\",status=\"404\"} 1\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{route=\"/bcast/{doc}\",le=\"0.005\"} 1\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{route=\"/bcast/{doc}\",le=\"+Inf\"} 2\n"));
+    }
+}
@@ -0,0 +1,253 @@
+//! Journal of all cache updates, for reviewing a session afterwards.
+//!
+//! A journal is a sequence of gzip members, each holding one or more JSON lines
+//! (see `Record`). Every batch of updates is written as a complete member and
+//! flushed, so a crash loses at most the member being written, and a reader
+//! simply stops at a truncated tail. Journals are rotated by size, parts of one
+//! session are named `<start time>-<part>.jsonl.gz`, a session started in the
+//! same second as an earlier one gets a suffix: `<start time>.<n>-<part>.jsonl.gz`.
+//!
+//! Derived documents (metrics, wbal, zones) are not recorded, a replay
+//! computes them again from focus.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::SystemTime;
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use crate::cache::{epoch_ms, Cache, Update};
+use crate::metrics;
+
+/// A single cache update as written to the journal.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Receive time in milliseconds since the epoch.
+    pub time: u64,
+    /// Document name, e.g. "focus".
+    pub name: String,
+    /// Document content as read from the broadcast file.
+    pub raw: String,
+}
+
+impl Record {
+    fn from_update(update: &Update) -> Record {
+        Record {
+            time: epoch_ms(update.time),
+            name: update.name.clone(),
+            raw: update.content.to_string(),
+        }
+    }
+}
+
+/// Writer for a rotating journal.
+pub struct Journal {
+    dir: PathBuf,
+    session: String,
+    part: u32,
+    max_size: u64,
+    file: File,
+    size: u64,
+}
+
+impl Journal {
+    /// Start a new session journal in the given directory.
+    pub fn create(dir: PathBuf, max_size: u64) -> io::Result<Journal> {
+        fs::create_dir_all(&dir)?;
+
+        // never append to an earlier session, e.g. after a quick restart
+        let start = epoch_ms(SystemTime::now()) / 1000;
+        let mut suffix = 0;
+        let (session, file) = loop {
+            let session = match suffix {
+                0 => start.to_string(),
+                n => format!("{}.{}", start, n),
+            };
+            match Journal::open(&dir, &session, 0) {
+                Ok(file) => break (session, file),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => suffix += 1,
+                Err(e) => return Err(e),
+            }
+        };
+
+        Ok(Journal { dir, session, part: 0, max_size, file, size: 0 })
+    }
+
+    fn open(dir: &Path, session: &str, part: u32) -> io::Result<File> {
+        let path = dir.join(format!("{}-{:03}.jsonl.gz", session, part));
+        let file = OpenOptions::new().create_new(true).append(true).open(&path)?;
+        log::info!("Recording to {}", path.display());
+
+        Ok(file)
+    }
+
+    /// Append records as one gzip member, rotating first if the current part is full.
+    pub fn append(&mut self, records: &[Record]) -> io::Result<()> {
+        if self.size >= self.max_size {
+            self.part += 1;
+            self.file = Journal::open(&self.dir, &self.session, self.part)?;
+            self.size = 0;
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        for record in records {
+            serde_json::to_writer(&mut encoder, record)?;
+            encoder.write_all(b"\n")?;
+        }
+        let member = encoder.finish()?;
+
+        self.file.write_all(&member)?;
+        self.file.sync_data()?;
+        self.size += member.len() as u64;
+        Ok(())
+    }
+}
+
+/// Record every cache update into a journal in `dir` from its own thread.
+///
+/// Updates are queued, so a slow disk never blocks the watcher.
+///
+/// The thread ends once the cache is closed and everything queued is written.
+pub fn start(cache: &Cache, dir: PathBuf, max_size: u64) -> io::Result<thread::JoinHandle<()>> {
+    let mut journal = Journal::create(dir, max_size)?;
+    let rx = cache.subscribe();
+
+    let handle = thread::spawn(move || {
+        while let Ok(update) = rx.recv() {
+            // write everything queued up in one go
+            let records: Vec<Record> = std::iter::once(update)
+                .chain(rx.try_iter())
+                .filter(|update| !metrics::DERIVED.contains(&update.name.as_str()))
+                .map(|update| Record::from_update(&update))
+                .collect();
+
+            if records.is_empty() {
+                continue;
+            }
+            if let Err(e) = journal.append(&records) {
+                log::warn!("Failed to record {} updates: {}", records.len(), e);
+            }
+        }
+    });
+
+    Ok(handle)
+}
+
+/// Read all records of a session journal, starting with the given part.
+///
+/// Following parts of the same session are read as well. A truncated tail
+/// (e.g. after a crash) ends a part without an error.
+pub fn read_journal(path: &Path) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    read_part(path, &mut records)?;
+
+    // <session>-<part>.jsonl.gz, look for the parts after this one
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    if let Some((session, part)) = name.strip_suffix(".jsonl.gz").and_then(|n| n.rsplit_once('-')) {
+        if let Ok(mut part) = part.parse::<u32>() {
+            loop {
+                part += 1;
+                let next = path.with_file_name(format!("{}-{:03}.jsonl.gz", session, part));
+                if !next.is_file() {
+                    break;
+                }
+                read_part(&next, &mut records)?;
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+fn read_part(path: &Path, records: &mut Vec<Record>) -> io::Result<()> {
+    log::info!("Reading journal {}", path.display());
+
+    let reader = BufReader::new(MultiGzDecoder::new(File::open(path)?));
+
+    for line in reader.lines() {
+        match line.map(|l| serde_json::from_str::<Record>(&l)) {
+            Ok(Ok(record)) => records.push(record),
+            Ok(Err(e)) => {
+                log::warn!("Journal {} ends with a broken record: {}", path.display(), e);
+                break;
+            },
+            Err(e) => {
+                log::warn!("Journal {} is truncated: {}", path.display(), e);
+                break;
+            },
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod recorder_should {
+    use super::*;
+
+    #[test]
+    fn rotate_and_append_complete_members() {
+        let dir = std::env::temp_dir().join(format!("tpvbc2http-recorder-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let record = Record { time: 1, name: String::from("focus"), raw: String::from("[\n{}\n]") };
+        let mut journal = Journal::create(dir.clone(), 1).unwrap();
+        journal.append(&[record.clone(), record.clone()]).unwrap();
+        journal.append(std::slice::from_ref(&record)).unwrap();
+
+        let mut parts: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        parts.sort();
+        assert_eq!(2, parts.len());
+
+        let mut records = Vec::new();
+        read_part(&parts[0], &mut records).unwrap();
+        assert_eq!(vec![record.clone(), record.clone()], records);
+
+        // all parts, even if the last one got truncated
+        let mut file = OpenOptions::new().append(true).open(&parts[1]).unwrap();
+        file.write_all(&[0x1f, 0x8b, 0x08]).unwrap();
+        assert_eq!(vec![record.clone(), record.clone(), record], read_journal(&parts[0]).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn start_a_new_file_for_each_session() {
+        let dir = std::env::temp_dir().join(format!("tpvbc2http-recorder-sessions-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let first = Record { time: 2000, name: String::from("focus"), raw: String::from("[1]") };
+        let second = Record { time: 1000, name: String::from("focus"), raw: String::from("[2]") };
+        Journal::create(dir.clone(), 1 << 20).unwrap().append(std::slice::from_ref(&first)).unwrap();
+        Journal::create(dir.clone(), 1 << 20).unwrap().append(std::slice::from_ref(&second)).unwrap();
+
+        let mut parts: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        parts.sort();
+        assert_eq!(2, parts.len());
+        assert_eq!(vec![first], read_journal(&parts[0]).unwrap());
+        assert_eq!(vec![second], read_journal(&parts[1]).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skip_derived_documents() {
+        let dir = std::env::temp_dir().join(format!("tpvbc2http-recorder-derived-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let cache = Cache::new();
+        let recorder = start(&cache, dir.clone(), 1 << 20).unwrap();
+        cache.update(metrics::METRICS, String::from("[1]"));
+        cache.update("focus", String::from("[2]"));
+        cache.update(metrics::ZONES, String::from("[3]"));
+        cache.close();
+        recorder.join().unwrap();
+
+        let part = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let names: Vec<String> = read_journal(&part).unwrap().into_iter().map(|record| record.name).collect();
+        assert_eq!(vec![String::from("focus")], names);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,444 @@
+//! Replay of a recorded session into the cache, as if it were live.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::json;
+use simple_server::{Method, Request, ResponseBuilder, ResponseResult, StatusCode};
+use crate::recorder::Record;
+use crate::cache::Cache;
+use crate::server::query_param;
+
+/// Pause between the end of the journal and the start of the next loop.
+const LOOP_GAP_MS: f64 = 1000.0;
+
+/// Slowest and fastest speed factor, beyond them the wait for the next record overflows.
+pub const MIN_SPEED: f64 = 0.01;
+pub const MAX_SPEED: f64 = 1000.0;
+
+/// Session time (milliseconds since the first record), scaled by speed and stopped while paused.
+struct Clock {
+    anchor: Instant,
+    anchor_offset: f64,
+    speed: f64,
+    paused: bool,
+}
+
+impl Clock {
+    fn now(&self) -> f64 {
+        if self.paused {
+            self.anchor_offset
+        } else {
+            self.anchor_offset + self.anchor.elapsed().as_secs_f64() * 1000.0 * self.speed
+        }
+    }
+
+    fn set(&mut self, offset: f64) {
+        self.anchor = Instant::now();
+        self.anchor_offset = offset;
+    }
+}
+
+struct State {
+    clock: Clock,
+    position: usize,
+    looping: bool,
+    stopped: bool,
+}
+
+/// A running replay, controlled through its methods or `handle`.
+pub struct Replay {
+    cache: Cache,
+    records: Vec<Record>,
+    /// Content of every document in the journal before the replay started.
+    initial: HashMap<String, String>,
+    state: Mutex<State>,
+    wakeup: Condvar,
+}
+
+impl Replay {
+    /// Start replaying `records` into `cache` from its own thread, which ends after `stop`.
+    ///
+    /// The speed is clamped to `MIN_SPEED..=MAX_SPEED`.
+    pub fn start(cache: Cache, records: Vec<Record>, speed: f64, looping: bool) -> (Arc<Replay>, thread::JoinHandle<()>) {
+        let initial = records.iter()
+            .map(|record| {
+                let content = cache.get(&record.name).map(|content| content.to_string()).unwrap_or_else(|| String::from("[]"));
+                (record.name.clone(), content)
+            })
+            .collect();
+
+        let replay = Arc::new(Replay {
+            cache,
+            records,
+            initial,
+            state: Mutex::new(State {
+                clock: Clock { anchor: Instant::now(), anchor_offset: 0.0, speed: speed.clamp(MIN_SPEED, MAX_SPEED), paused: false },
+                position: 0,
+                looping,
+                stopped: false,
+            }),
+            wakeup: Condvar::new(),
+        });
+
+        log::info!("Replay of {} records ({:.0}s) started", replay.records.len(), replay.duration() / 1000.0);
+
+        let runner = replay.clone();
+        let thread = thread::spawn(move || runner.run());
+
+        (replay, thread)
+    }
+
+    /// Offset of a record from the start of the session in milliseconds.
+    fn offset(&self, index: usize) -> f64 {
+        self.records[index].time.saturating_sub(self.records[0].time) as f64
+    }
+
+    fn duration(&self) -> f64 {
+        match self.records.len() {
+            0 => 0.0,
+            n => self.offset(n - 1),
+        }
+    }
+
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        while !state.stopped {
+            let now = state.clock.now();
+
+            let due = if state.position < self.records.len() {
+                self.offset(state.position)
+            } else if state.looping && !self.records.is_empty() {
+                self.duration() + LOOP_GAP_MS
+            } else {
+                state = self.wakeup.wait(state).unwrap();
+                continue;
+            };
+
+            if due <= now {
+                if state.position < self.records.len() {
+                    let record = &self.records[state.position];
+                    self.cache.update(&record.name, record.raw.clone());
+                    state.position += 1;
+                } else {
+                    log::info!("Replay restarts");
+                    self.seek_locked(&mut state, 0.0);
+                }
+            } else if state.clock.paused {
+                state = self.wakeup.wait(state).unwrap();
+            } else {
+                let wait = Duration::from_secs_f64((due - now) / 1000.0 / state.clock.speed);
+                state = self.wakeup.wait_timeout(state, wait).unwrap().0;
+            }
+        }
+    }
+
+    // Bring the cache to the state at `offset` by applying the latest record of every document,
+    // documents without a record up to there get their content from before the replay.
+    fn seek_locked(&self, state: &mut MutexGuard<State>, offset: f64) {
+        let end = (0..self.records.len())
+            .position(|i| self.offset(i) > offset)
+            .unwrap_or(self.records.len());
+
+        let mut latest = HashMap::new();
+        for (i, record) in self.records[..end].iter().enumerate() {
+            latest.insert(record.name.as_str(), i);
+        }
+        for (name, content) in &self.initial {
+            if !latest.contains_key(name.as_str()) {
+                self.cache.update(name, content.clone());
+            }
+        }
+
+        let mut latest: Vec<usize> = latest.into_values().collect();
+        latest.sort();
+
+        for i in latest {
+            self.cache.update(&self.records[i].name, self.records[i].raw.clone());
+        }
+
+        state.position = end;
+        state.clock.set(offset);
+    }
+
+    fn control<F: FnOnce(&Replay, &mut MutexGuard<State>)>(&self, f: F) {
+        let mut state = self.state.lock().unwrap();
+        f(self, &mut state);
+        self.wakeup.notify_all();
+    }
+
+    pub fn pause(&self) {
+        self.control(|_, state| {
+            let now = state.clock.now();
+            state.clock.set(now);
+            state.clock.paused = true;
+        });
+    }
+
+    pub fn resume(&self) {
+        self.control(|_, state| {
+            let now = state.clock.now();
+            state.clock.set(now);
+            state.clock.paused = false;
+        });
+    }
+
+    /// Jump to a position in the session, backwards or forwards.
+    pub fn seek(&self, to: Duration) {
+        self.control(|replay, state| replay.seek_locked(state, to.as_secs_f64() * 1000.0));
+    }
+
+    /// Speed factor, e.g. 2.0 replays twice as fast as recorded, clamped to `MIN_SPEED..=MAX_SPEED`.
+    pub fn set_speed(&self, speed: f64) {
+        self.control(|_, state| {
+            let now = state.clock.now();
+            state.clock.set(now);
+            state.clock.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        });
+    }
+
+    pub fn set_looping(&self, looping: bool) {
+        self.control(|_, state| state.looping = looping);
+    }
+
+    /// End the replay thread, checked between records.
+    pub fn stop(&self) {
+        self.control(|_, state| state.stopped = true);
+    }
+
+    /// Current state, times in seconds.
+    pub fn status(&self) -> String {
+        let state = self.state.lock().unwrap();
+
+        json!({
+            "position": state.position,
+            "records": self.records.len(),
+            "offset": state.clock.now().min(self.duration()) / 1000.0,
+            "duration": self.duration() / 1000.0,
+            "speed": state.clock.speed,
+            "paused": state.clock.paused,
+            "loop": state.looping,
+        }).to_string()
+    }
+
+    /// Serve the /replay/ routes: GET status, POST pause, resume, seek?to=<s>, speed?factor=<f>, loop?enabled=<bool>.
+    pub fn handle(&self, request: &Request<Vec<u8>>, mut response: ResponseBuilder) -> ResponseResult {
+        let action = &request.uri().path()[8..];
+        let param = |key| query_param(request.uri().query(), key);
+
+        let error = match (request.method(), action) {
+            (&Method::GET, "status") => None,
+            (&Method::POST, "pause") => {
+                self.pause();
+                None
+            },
+            (&Method::POST, "resume") => {
+                self.resume();
+                None
+            },
+            (&Method::POST, "seek") => match param("to").and_then(|v| v.parse::<f64>().ok()) {
+                Some(to) if to.is_finite() && to >= 0.0 => {
+                    self.seek(Duration::from_secs_f64(to.min(self.duration() / 1000.0)));
+                    None
+                },
+                _ => Some("expected to=<seconds>"),
+            },
+            (&Method::POST, "speed") => match param("factor").and_then(|v| v.parse::<f64>().ok()) {
+                Some(factor) if (MIN_SPEED..=MAX_SPEED).contains(&factor) => {
+                    self.set_speed(factor);
+                    None
+                },
+                _ => Some("expected factor=<number from 0.01 to 1000>"),
+            },
+            (&Method::POST, "loop") => match param("enabled").and_then(|v| v.parse::<bool>().ok()) {
+                Some(enabled) => {
+                    self.set_looping(enabled);
+                    None
+                },
+                _ => Some("expected enabled=<true|false>"),
+            },
+            _ => {
+                response.status(StatusCode::NOT_FOUND);
+                return Ok(response.body("<h1>404</h1><p>Page not found!<p>".as_bytes().to_vec())?);
+            },
+        };
+
+        match error {
+            None => {
+                response.header("content-type", "text/json");
+                Ok(response.body(self.status().into_bytes())?)
+            },
+            Some(e) => {
+                response.status(StatusCode::BAD_REQUEST);
+                Ok(response.body(format!("<h1>400</h1><p>{}<p>", e).into_bytes())?)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod replay_should {
+    use super::*;
+    use serde_json::Value;
+    use simple_server::Response;
+    use std::sync::mpsc::Receiver;
+    use crate::cache::Update;
+
+    fn record(time: u64, name: &str, raw: &str) -> Record {
+        Record { time, name: String::from(name), raw: String::from(raw) }
+    }
+
+    // next update as (name, content) and when it was published
+    fn next(updates: &Receiver<Update>) -> (String, String, Instant) {
+        let update = updates.recv_timeout(Duration::from_secs(2)).unwrap();
+        (update.name, update.content.to_string(), Instant::now())
+    }
+
+    fn post(replay: &Replay, uri: &str) -> (StatusCode, Value) {
+        let request = Request::builder().method(Method::POST).uri(uri).body(Vec::new()).unwrap();
+        let response = replay.handle(&request, Response::builder()).unwrap();
+        let status: Value = serde_json::from_str(&replay.status()).unwrap();
+        (response.status(), status)
+    }
+
+    #[test]
+    fn deliver_records_in_order_scaled_by_speed() {
+        let cache = Cache::new();
+        let updates = cache.subscribe();
+        let records = vec![
+            record(10_000, "event", "[1]"),
+            record(10_200, "results", "[1]"),
+            record(10_400, "event", "[2]"),
+        ];
+        let start = Instant::now();
+        let (replay, thread) = Replay::start(cache.clone(), records, 2.0, false);
+
+        let expected = [("event", "[1]", 0), ("results", "[1]", 100), ("event", "[2]", 200)];
+        for (name, raw, at) in expected {
+            let (got_name, got_raw, got_at) = next(&updates);
+            assert_eq!((name, raw), (got_name.as_str(), got_raw.as_str()));
+            let elapsed = got_at.duration_since(start).as_millis() as u64;
+            assert!(elapsed + 10 >= at, "{} at {}ms, expected {}ms", name, elapsed, at);
+        }
+
+        replay.stop();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn continue_from_the_same_offset_after_pause() {
+        let cache = Cache::new();
+        let updates = cache.subscribe();
+        let records = vec![
+            record(0, "event", "[1]"),
+            record(200, "event", "[2]"),
+        ];
+        let (replay, thread) = Replay::start(cache.clone(), records, 1.0, false);
+
+        assert_eq!("[1]", next(&updates).1);
+        replay.pause();
+        thread::sleep(Duration::from_millis(400));
+        assert!(updates.try_recv().is_err());
+        let status: Value = serde_json::from_str(&replay.status()).unwrap();
+        assert!(status["offset"].as_f64().unwrap() < 0.1);
+
+        let resumed = Instant::now();
+        replay.resume();
+        let (_, raw, at) = next(&updates);
+        assert_eq!("[2]", raw);
+        assert!(at.duration_since(resumed) >= Duration::from_millis(120));
+
+        replay.stop();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn restore_earlier_documents_on_seek() {
+        let cache = Cache::new();
+        let records = vec![
+            record(0, "event", "[1]"),
+            record(100, "results", "[1]"),
+            record(200, "results", "[2]"),
+            record(60_000, "event", "[2]"),
+        ];
+        let (replay, thread) = Replay::start(cache.clone(), records, 1.0, false);
+        replay.pause();
+        replay.seek(Duration::from_millis(250));
+        assert_eq!((Some("[1]"), Some("[2]")), (cache.get("event").as_deref(), cache.get("results").as_deref()));
+        let status: Value = serde_json::from_str(&replay.status()).unwrap();
+        assert_eq!(3, status["position"]);
+
+        replay.seek(Duration::from_millis(150));
+        assert_eq!(Some("[1]"), cache.get("results").as_deref());
+        let status: Value = serde_json::from_str(&replay.status()).unwrap();
+        assert_eq!(2, status["position"]);
+
+        replay.stop();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn restore_documents_from_before_their_first_record() {
+        let cache = Cache::new();
+        cache.update("event", String::from("[0]"));
+        let records = vec![
+            record(0, "results", "[1]"),
+            record(100, "event", "[1]"),
+            record(200, "groups", "[1]"),
+            record(60_000, "results", "[2]"),
+        ];
+        let (replay, thread) = Replay::start(cache.clone(), records, 1.0, false);
+        replay.pause();
+        replay.seek(Duration::from_millis(250));
+        assert_eq!((Some("[1]"), Some("[1]")), (cache.get("event").as_deref(), cache.get("groups").as_deref()));
+
+        replay.seek(Duration::from_millis(50));
+        assert_eq!(Some("[1]"), cache.get("results").as_deref());
+        assert_eq!((Some("[0]"), Some("[]")), (cache.get("event").as_deref(), cache.get("groups").as_deref()));
+
+        replay.stop();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn wrap_back_to_the_start_when_looping() {
+        let cache = Cache::new();
+        let updates = cache.subscribe();
+        let records = vec![
+            record(0, "event", "[1]"),
+            record(100, "event", "[2]"),
+        ];
+        // 100ms of records and the gap before the next loop, in 110ms
+        let (replay, thread) = Replay::start(cache.clone(), records, 10.0, true);
+
+        let raws: Vec<String> = (0..5).map(|_| next(&updates).1).collect();
+        assert_eq!(["[1]", "[2]", "[1]", "[2]", "[1]"], raws.as_slice());
+
+        replay.stop();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn reject_out_of_range_seek_and_speed() {
+        let records = vec![
+            Record { time: 1000, name: String::from("focus"), raw: String::from("[1]") },
+            Record { time: 3000, name: String::from("focus"), raw: String::from("[2]") },
+        ];
+        let (replay, thread) = Replay::start(Cache::new(), records, 1e-300, false);
+        assert_eq!(MIN_SPEED, post(&replay, "/replay/pause").1["speed"]);
+
+        assert_eq!(StatusCode::BAD_REQUEST, post(&replay, "/replay/seek?to=inf").0);
+        assert_eq!(StatusCode::BAD_REQUEST, post(&replay, "/replay/seek?to=NaN").0);
+        let (code, status) = post(&replay, "/replay/seek?to=1e300");
+        assert_eq!((StatusCode::OK, 2.0), (code, status["offset"].as_f64().unwrap()));
+
+        assert_eq!(StatusCode::BAD_REQUEST, post(&replay, "/replay/speed?factor=1e-300").0);
+        assert_eq!(StatusCode::BAD_REQUEST, post(&replay, "/replay/speed?factor=inf").0);
+        assert_eq!(StatusCode::OK, post(&replay, "/replay/speed?factor=1000").0);
+
+        replay.stop();
+        thread.join().unwrap();
+    }
+}
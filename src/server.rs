@@ -1,222 +1,734 @@
 extern crate simple_server;
 
-use unicode_bom::Bom;
-use std::{fs, io, sync::{Arc, Mutex}, thread};
-use notify::{Event, RecursiveMode, Result, Watcher};
-use std::{path::Path, sync::mpsc};
-use simple_server::{Method, Server, StatusCode};
-
-pub struct CacheableJson {
-    data: Arc<Mutex<String>>,
+use std::{io, thread};
+use std::net::{SocketAddr, TcpListener};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use simple_server::{Method, Request, ResponseBuilder, ResponseResult, Server, Shutdown, Stats, StatusCode};
+use crate::activity::{self, Activity, Format};
+use crate::cache::{Cache, StaleAfter, Versioned, Waiters};
+use crate::config::{Endpoints, Rider};
+use crate::mapping::Mapping;
+use crate::{metrics, prometheus};
+use crate::recorder::{self, Record};
+use crate::replay::Replay;
+use crate::source::{self, BroadcastSource, SourceHandle, WatcherBackend};
+use crate::table::Table;
+use crate::team::{self, TEAM};
+use crate::{sse, ws};
+
+/// What is served for a stale document.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StalePayload {
+    /// The last content, as if it was current.
+    Last,
+    /// An empty array.
+    Empty,
+    /// `[{"offline":true}]`, so overlays can tell TPV is gone.
+    Offline,
 }
 
-impl CacheableJson {
-    pub fn new() -> CacheableJson {
-        CacheableJson { 
-            data: Arc::new(Mutex::new(String::from("[]"))), 
-        }
-    }
+pub struct Instance {
+    cache: Cache,
+    sources: BTreeMap<String, (PathBuf, Cache)>,
+    rescan_interval: Option<Duration>,
+    debounce: Duration,
+    watcher: WatcherBackend,
+    record: Option<(PathBuf, u64)>,
+    replay: Option<(Vec<Record>, f64, bool)>,
+    static_dir: Option<PathBuf>,
+    endpoints: Endpoints,
+    rider: Rider,
+    stale_after: StaleAfter,
+    stale_payload: StalePayload,
+    threads: Option<u32>,
+    shutdown_timeout: Option<Duration>,
 }
 
-pub struct Cache {
-    focus       : CacheableJson,
-    nearest     : CacheableJson,
-    entries     : CacheableJson,
-    event       : CacheableJson,
-    groups      : CacheableJson,
-    results_indv: CacheableJson,
-    results_team: CacheableJson, 
+/// A running server, see `Instance::start`.
+#[derive(Clone)]
+pub struct Handle {
+    cache: Cache,
+    caches: Vec<Cache>,
+    local_addr: SocketAddr,
+    shutdown: Shutdown,
+    thread: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
 }
 
-impl Cache {
-    pub fn new() -> Cache {
-        Cache { 
-            focus       : CacheableJson::new(),
-            nearest     : CacheableJson::new(),
-            entries     : CacheableJson::new(),
-            event       : CacheableJson::new(),
-            groups      : CacheableJson::new(),
-            results_indv: CacheableJson::new(),
-            results_team: CacheableJson::new(),         
+impl Default for Instance {
+    fn default() -> Instance {
+        Instance::new()
+    }
+}
+
+impl Instance {
+    pub fn new() -> Instance {
+        Instance {
+            cache: Cache::new(),
+            sources: BTreeMap::new(),
+            rescan_interval: None,
+            debounce: source::DEFAULT_DEBOUNCE,
+            watcher: WatcherBackend::Native,
+            record: None,
+            replay: None,
+            static_dir: None,
+            endpoints: Endpoints::default(),
+            rider: Rider::default(),
+            stale_after: StaleAfter::default(),
+            stale_payload: StalePayload::Last,
+            threads: None,
+            shutdown_timeout: None,
         }
     }
 
-    pub fn focus_data(&self) -> Arc<Mutex<String>> {
-        self.focus.data.clone()
+    /// The cache served by this instance, e.g. to subscribe before starting.
+    pub fn cache(&self) -> &Cache {
+        &self.cache
     }
 
-    pub fn nearest_data(&self) -> Arc<Mutex<String>> {
-        self.nearest.data.clone()
+    /// Expose broadcast files according to `mapping` instead of the default one.
+    pub fn set_mapping(&mut self, mapping: Mapping) {
+        for (_, cache) in self.sources.values_mut() {
+            *cache = Cache::with_mapping(mapping.clone());
+        }
+        self.cache = Cache::with_mapping(mapping);
     }
 
-    pub fn entries_data(&self) -> Arc<Mutex<String>> {
-        self.entries.data.clone()
+    /// Watch another broadcast directory, served below `/bcast/<name>/` and
+    /// aggregated with the other named sources below `/bcast/team/`.
+    pub fn add_source<P: Into<PathBuf>>(&mut self, name: &str, dir: P) -> Result<(), String> {
+        if name == TEAM || !Mapping::valid_name(name) {
+            return Err(format!("invalid source name \"{}\"", name));
+        }
+
+        let cache = Cache::with_mapping(self.cache.mapping().clone());
+        self.sources.insert(name.to_string(), (dir.into(), cache));
+        Ok(())
     }
 
-    pub fn event_data(&self) -> Arc<Mutex<String>> {
-        self.event.data.clone()
+    /// Only serve the enabled routes, all are enabled by default.
+    pub fn set_endpoints(&mut self, endpoints: Endpoints) {
+        self.endpoints = endpoints;
     }
 
-    pub fn groups_data(&self) -> Arc<Mutex<String>> {
-        self.groups.data.clone()
+    /// Rider the metrics documents are computed for.
+    pub fn set_rider(&mut self, rider: Rider) {
+        self.rider = rider;
     }
 
-    pub fn results_indv_data(&self) -> Arc<Mutex<String>> {
-        self.results_indv.data.clone()
+    /// Number of threads handling connections, `None` for the server default.
+    pub fn set_threads(&mut self, threads: Option<u32>) {
+        self.threads = threads;
     }
 
-    pub fn results_team_data(&self) -> Arc<Mutex<String>> {
-        self.results_team.data.clone()
+    /// How long responses in progress may take to finish on shutdown, `None` for the server default.
+    pub fn set_shutdown_timeout(&mut self, timeout: Option<Duration>) {
+        self.shutdown_timeout = timeout;
     }
-}
 
-pub struct Instance {
-    cache: Cache,
-}
+    /// Serve static files (e.g. the TPVUI overlays) from `dir` next to the broadcast data.
+    pub fn set_static_dir<P: Into<PathBuf>>(&mut self, dir: P) {
+        self.static_dir = Some(dir.into());
+    }
 
-impl Instance {
-    pub fn new() -> Instance {
-        Instance {
-            cache: Cache::new(),
-        }
+    /// Feed recorded updates into the cache instead of watching the broadcast directory.
+    pub fn set_replay(&mut self, records: Vec<Record>, speed: f64, looping: bool) {
+        self.replay = Some((records, speed, looping));
     }
 
-    fn read_from_fs(fname: &str) -> io::Result<String> {
-        match fs::read_to_string(fname) {
-            Ok(conten) => {
-                // if there is a leading BOM, remove it ...
-                let bom = Bom::from(conten.as_bytes());
-                Ok(conten[bom.len()..].to_string())
-            },
-            Err(err) => Err(err),
+    /// Record every cache update to a journal in `dir`, rotated after `max_size` bytes.
+    ///
+    /// Each named source is recorded to a journal of its own in `dir/<name>`.
+    pub fn set_record_dir<P: Into<PathBuf>>(&mut self, dir: P, max_size: u64) {
+        self.record = Some((dir.into(), max_size));
+    }
+
+    /// Periodically reload all broadcast files, in case the watcher misses an event.
+    ///
+    /// Disabled by default, pass `None` to disable it again.
+    pub fn set_rescan_interval(&mut self, interval: Option<Duration>) {
+        self.rescan_interval = interval;
+    }
+
+    /// Time to wait for further changes of a broadcast file before reloading it.
+    pub fn set_debounce(&mut self, debounce: Duration) {
+        self.debounce = debounce;
+    }
+
+    /// When documents are marked stale, and what is served for them then.
+    ///
+    /// Documents are never stale by default.
+    pub fn set_stale(&mut self, stale_after: StaleAfter, payload: StalePayload) {
+        self.stale_after = stale_after;
+        self.stale_payload = payload;
+    }
+
+    /// How broadcast directories are watched, file system events by default.
+    pub fn set_watcher(&mut self, backend: WatcherBackend) {
+        self.watcher = backend;
+    }
+
+    fn watch(&self, dir: &Path, cache: &Cache) -> io::Result<SourceHandle> {
+        let mut source = BroadcastSource::new(dir);
+        source.set_rescan_interval(self.rescan_interval);
+        source.set_debounce(self.debounce);
+        source.set_watcher(self.watcher);
+        source.start(cache)
+            .map_err(|e| io::Error::other(format!("watching {} failed: {}", dir.display(), e)))
+    }
+
+    fn close(&self) {
+        self.cache.close();
+        for (_, cache) in self.sources.values() {
+            cache.close();
         }
     }
 
-    fn start_cache(&self, path: String) {
-        // access to cache data
-        let focus = self.cache.focus_data();
-        let nearest = self.cache.nearest_data();
-        let entries = self.cache.entries_data();
-        let event = self.cache.event_data();
-        let groups = self.cache.groups_data();
-        let results_indv = self.cache.results_indv_data();
-        let results_team = self.cache.results_team_data();
-
-        thread::spawn(move || {
-            log::info!("Cache started on {}", path);
-
-            let (tx, rx) = mpsc::channel::<Result<Event>>();
-            let mut watcher = notify::recommended_watcher(tx).unwrap();
-            watcher.watch(Path::new(&path), RecursiveMode::NonRecursive).unwrap();
-
-            let is_linux = cfg!(target_os = "linux");
-
-            for res in rx {
-                match res {
-                    Ok(e) => {
-                        log::debug!("event: {:?}", e);
-                        if (is_linux && e.kind.is_access()) || (!is_linux && e.kind.is_modify()) {
-                            for p in e.paths {
-                                match Instance::read_from_fs(p.to_str().unwrap()) {
-                                    Ok(content) => {
-                                        if p.ends_with("focus.json") {
-                                            let mut focus_locked = focus.lock().unwrap();
-                                            *focus_locked = content;
-                                            // notify change listeners
-                                            log::info!("Updated cache for focus data");
-                                        } else if p.ends_with("nearest.json") {
-                                            let mut nearest_locked = nearest.lock().unwrap();
-                                            *nearest_locked = content;
-                                            log::info!("Updated cache for nearest data");
-                                        } else if p.ends_with("entries.json") {
-                                            let mut entries_locked = entries.lock().unwrap();
-                                            *entries_locked = content;
-                                            log::info!("Updated cache for entries data");
-                                        } else if p.ends_with("event.json") {
-                                            let mut event_locked = event.lock().unwrap();
-                                            *event_locked = content;
-                                            log::info!("Updated cache for event data");
-                                        } else if p.ends_with("groups.json") {
-                                            let mut groups_locked = groups.lock().unwrap();
-                                            *groups_locked = content;
-                                            log::info!("Updated cache for groups data");
-                                        } else if p.ends_with("resultsIndv.json") {
-                                            let mut results_indv_locked = results_indv.lock().unwrap();
-                                            *results_indv_locked = content;
-                                            log::info!("Updated cache for results_indv data");
-                                        } else if p.ends_with("resultsTeam.json") {
-                                            let mut results_team_locked = results_team.lock().unwrap();
-                                            *results_team_locked = content;
-                                            log::info!("Updated cache for results_team data");
-                                        }
-                                    },
-                                    Err(_) => (),    // this is usually windows complaining about file being open in other process
-                                }
-                            }
-                        }
-                    },
-                    Err(e) => log::warn!("watch error: {:?}", e),
+    /// Start watching `path` and the named sources (or replaying) and serving on `host`:`port` in the background.
+    ///
+    /// Fails if the address can't be bound or the directory can't be watched,
+    /// a directory which does not exist yet is waited for.
+    pub fn start(self, host: &str, port: &str, path: Option<String>) -> io::Result<Handle> {
+        let listener = TcpListener::bind(format!("{}:{}", host, port))?;
+        let local_addr = listener.local_addr()?;
+
+        // before the caches are filled, so no focus sample is missed
+        let waiters = Arc::new(Waiters::default());
+        for cache in std::iter::once(&self.cache).chain(self.sources.values().map(|(_, cache)| cache)) {
+            metrics::start(cache, self.rider.clone());
+            cache.set_stale_after(self.stale_after.clone());
+            cache.set_waiters(waiters.clone());
+        }
+        let (activity, _) = activity::start(&self.cache);
+        let activities: BTreeMap<String, Arc<Mutex<Activity>>> = self.sources.iter()
+            .map(|(name, (_, cache))| (name.clone(), activity::start(cache).0))
+            .collect();
+
+        // before the cache, so the initial content is recorded too
+        let mut recorders = Vec::new();
+        if let Some((dir, max_size)) = &self.record {
+            let journals = std::iter::once((dir.clone(), &self.cache))
+                .chain(self.sources.iter().map(|(name, (_, cache))| (dir.join(name), cache)));
+
+            for (dir, cache) in journals {
+                match recorder::start(cache, dir.clone(), *max_size) {
+                    Ok(handle) => recorders.push(handle),
+                    Err(e) => log::error!("Failed to start recording to {}: {}", dir.display(), e),
                 }
             }
-        });
-    }
+        }
+
+        let (replay, replay_thread) = match &self.replay {
+            Some((records, speed, looping)) => {
+                let (replay, thread) = Replay::start(self.cache.clone(), records.clone(), *speed, *looping);
+                (Some(replay), Some(thread))
+            },
+            None => (None, None),
+        };
+        let stopping = replay.clone();
 
-    pub fn start(&self, host: &str, port: &str, path: String) {
-        self.start_cache(path.clone());
+        // the default directory is only watched when not replaying
+        let path = path.filter(|_| replay.is_none()).map(PathBuf::from);
+        let dirs = path.iter().map(|dir| (dir.as_path(), &self.cache))
+            .chain(self.sources.values().map(|(dir, cache)| (dir.as_path(), cache)));
 
-        // access to cache data
-        let focus = self.cache.focus_data();
-        let nearest = self.cache.nearest_data();
-        let entries = self.cache.entries_data();
-        let event = self.cache.event_data();
-        let groups = self.cache.groups_data();
-        let results_indv = self.cache.results_indv_data();
-        let results_team = self.cache.results_team_data();
+        let mut watchers = Vec::new();
+        for (dir, cache) in dirs {
+            match self.watch(dir, cache) {
+                Ok(watcher) => watchers.push(watcher),
+                Err(e) => {
+                    self.close();
+                    return Err(e);
+                },
+            }
+        }
+
+        let sources: Arc<BTreeMap<String, Cache>> = Arc::new(self.sources.iter()
+            .map(|(name, (_, cache))| (name.clone(), cache.clone()))
+            .collect());
+        let cache = self.cache.clone();
+        let endpoints = self.endpoints;
+        let stale_payload = self.stale_payload;
+        let stats: Arc<OnceLock<Stats>> = Arc::new(OnceLock::new());
+        let requests = stats.clone();
 
         let mut server = Server::new( move |request, mut response| {
             log::info!("Received: {} {}", request.method(), request.uri());
 
-            if request.method() == &Method::GET && request.uri().path().starts_with("/bcast/") {
+            if *request.method() == Method::GET && request.uri().path().starts_with("/bcast/") {
                 let uri= &request.uri().path()[7..];
 
-                if uri == "focus" {
-                    let focus_locked = focus.lock().unwrap();
-                    response.header("content-type", "text/json");
-                    Ok(response.body(focus_locked.as_bytes().to_vec())?)
-                } else if uri == "nearest" {
-                    let nearest_locked = nearest.lock().unwrap();
-                    response.header("content-type", "text/json");
-                    Ok(response.body(nearest_locked.as_bytes().to_vec())?)
-                } else if uri == "entries" {
-                    let entries_locked = entries.lock().unwrap();
-                    response.header("content-type", "text/json");
-                    Ok(response.body(entries_locked.as_bytes().to_vec())?)
-                } else if uri == "event" {
-                    let event_locked = event.lock().unwrap();
-                    response.header("content-type", "text/json");
-                    Ok(response.body(event_locked.as_bytes().to_vec())?)
-                } else if uri == "groups" {
-                    let groups_locked = groups.lock().unwrap();
-                    response.header("content-type", "text/json");
-                    Ok(response.body(groups_locked.as_bytes().to_vec())?)
-                } else if uri == "resultsIndv" {
-                    let results_indv_locked = results_indv.lock().unwrap();
-                    response.header("content-type", "text/json");
-                    Ok(response.body(results_indv_locked.as_bytes().to_vec())?)
-                }  else if uri == "resultsTeam" {
-                    let results_team_locked = results_team.lock().unwrap();
-                    response.header("content-type", "text/json");
-                    Ok(response.body(results_team_locked.as_bytes().to_vec())?)
+                if let Some((source, uri)) = uri.split_once('/') {
+                    if source == TEAM {
+                        let csv = uri.strip_suffix(".csv");
+                        match team::aggregate(&sources, csv.unwrap_or(uri)).filter(|_| endpoints.documents) {
+                            Some(data) if csv.is_some() => serve_csv(&data, response),
+                            Some(data) => {
+                                response.header("content-type", "text/json");
+                                Ok(response.body(data.into_bytes())?)
+                            },
+                            None => {
+                                response.status(StatusCode::NOT_FOUND);
+                                Ok(response.body("<h1>404</h1><p>File not found!<p>".as_bytes().to_vec())?)
+                            },
+                        }
+                    } else if let Some(cache) = sources.get(source) {
+                        serve_documents(cache, &endpoints, stale_payload, &format!("/bcast/{}/", source), uri, &request, response)
+                    } else {
+                        response.status(StatusCode::NOT_FOUND);
+                        Ok(response.body("<h1>404</h1><p>Source not found!<p>".as_bytes().to_vec())?)
+                    }
+                } else if uri == "stream" && endpoints.stream {
+                    let topics = match query_param(request.uri().query(), "topics") {
+                        Some(topics) => topics.split(',').map(String::from).collect(),
+                        None => cache.names(),
+                    };
+
+                    if let Some(t) = topics.iter().find(|t| cache.get(t).is_none()) {
+                        response.status(StatusCode::BAD_REQUEST);
+                        return Ok(response.body(format!("<h1>400</h1><p>Unknown topic: {}<p>", t).into_bytes())?);
+                    }
+
+                    let cache = cache.clone();
+                    let rx = cache.subscribe();
+                    response.header("content-type", "text/event-stream");
+                    response.header("cache-control", "no-cache");
+                    simple_server::stream(&mut response, move |stream| {
+                        sse::run(stream, &cache, &topics, rx)
+                    })
+                } else if uri == "ws" && endpoints.ws {
+                    let cache = cache.clone();
+                    simple_server::websocket::upgrade(&request, &mut response, move |socket| {
+                        ws::run(socket, cache)
+                    })
                 } else {
-                    response.status(StatusCode::NOT_FOUND);
-                    Ok(response.body("<h1>404</h1><p>File not found!<p>".as_bytes().to_vec())?)                       
+                    serve_documents(&cache, &endpoints, stale_payload, "/bcast/", uri, &request, response)
+                }
+            } else if *request.method() == Method::GET && endpoints.prometheus && request.uri().path() == "/metrics" {
+                let caches: Vec<(&str, &Cache)> = std::iter::once(("", &cache))
+                    .chain(sources.iter().map(|(name, cache)| (name.as_str(), cache)))
+                    .collect();
+                let requests = requests.get().map(Stats::snapshot).unwrap_or_default();
+
+                response.header("content-type", "text/plain; version=0.0.4; charset=utf-8");
+                Ok(response.body(prometheus::render(&caches, &requests).into_bytes())?)
+            } else if *request.method() == Method::GET && endpoints.export && request.uri().path().starts_with("/export/") {
+                let uri = &request.uri().path()[8..];
+
+                // /export/activity.<ext> for the default directory, /export/<source>/activity.<ext> for the others
+                let (activity, file) = match uri.split_once('/') {
+                    Some((source, file)) => (activities.get(source), file),
+                    None => (Some(&activity), uri),
+                };
+
+                match (activity, file.strip_prefix("activity.").and_then(Format::from_extension)) {
+                    (Some(activity), Some(format)) => serve_activity(&activity.lock().unwrap(), format, response),
+                    _ => {
+                        response.status(StatusCode::NOT_FOUND);
+                        Ok(response.body("<h1>404</h1><p>File not found!<p>".as_bytes().to_vec())?)
+                    },
                 }
+            } else if let (Some(replay), true) = (&replay, endpoints.replay && request.uri().path().starts_with("/replay/")) {
+                replay.handle(&request, response)
             } else {
                 response.status(StatusCode::NOT_FOUND);
                 Ok(response.body("<h1>404</h1><p>Page not found!<p>".as_bytes().to_vec())?)                
             }
         });   
-        server.dont_serve_static_files();
-        server.listen(host, port);
+        server.set_route_label(route_label);
+        let _ = stats.set(server.stats());
+        if let Some(threads) = self.threads {
+            server.set_pool_size(threads);
+        }
+        // long-polls may take half of the pool, the other half keeps serving
+        waiters.set_max(server.pool_size() as usize / 2);
+        if let Some(timeout) = self.shutdown_timeout {
+            server.set_shutdown_timeout(timeout);
+        }
+        match &self.static_dir {
+            Some(dir) => server.set_static_directory(dir),
+            None => server.dont_serve_static_files(),
+        }
+
+        let shutdown = server.shutdown_handle();
+        let caches: Vec<Cache> = std::iter::once(&self.cache)
+            .chain(self.sources.values().map(|(_, cache)| cache))
+            .cloned()
+            .collect();
+        let closing = caches.clone();
+
+        log::info!("Server started at http://{}", local_addr);
+        let thread = thread::spawn(move || {
+            server.listen_on_socket(listener);
+
+            // recorder, streams and sockets see the cache going away
+            drop(watchers);
+            for cache in caches {
+                cache.close();
+            }
+            for recorder in recorders {
+                let _ = recorder.join();
+            }
+            if let (Some(replay), Some(thread)) = (stopping, replay_thread) {
+                replay.stop();
+                let _ = thread.join();
+            }
+            log::info!("Stopped");
+        });
+
+        Ok(Handle {
+            cache: self.cache,
+            caches: closing,
+            local_addr,
+            shutdown,
+            thread: Arc::new(Mutex::new(Some(thread))),
+        })
+    }
+}
+
+impl Handle {
+    pub fn cache(&self) -> &Cache {
+        &self.cache
     }
-}
\ No newline at end of file
+
+    /// Address the server is bound to, e.g. to find the port when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop serving, responses in progress are finished first. Can be called from any thread.
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown();
+
+        // ends long-polls and streams, so they don't hold up draining the server
+        for cache in &self.caches {
+            cache.close();
+        }
+    }
+
+    /// Wait until the server is shut down and the watcher, recorder and replay are stopped.
+    pub fn wait(&self) {
+        let thread = self.thread.lock().unwrap().take();
+        if let Some(thread) = thread {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Route of a request in the request stats, without document or source names.
+fn route_label(request: &Request<Vec<u8>>) -> String {
+    let path = request.uri().path();
+
+    if let Some(uri) = path.strip_prefix("/bcast/") {
+        let (source, uri) = match uri.split_once('/') {
+            Some((TEAM, uri)) => ("team/", uri),
+            Some((_, uri)) => ("{source}/", uri),
+            None => ("", uri),
+        };
+        let document = match uri {
+            "" | "status" | "watcher" | "stream" | "ws" => uri,
+            _ if uri.ends_with(".csv") => "{doc}.csv",
+            _ => "{doc}",
+        };
+        format!("/bcast/{}{}", source, document)
+    } else if path.starts_with("/export/") {
+        String::from("/export/")
+    } else if path.starts_with("/replay/") {
+        String::from("/replay/")
+    } else if path == "/metrics" {
+        String::from("/metrics")
+    } else {
+        String::from("other")
+    }
+}
+
+/// The activity so far as training file to download.
+fn serve_activity(activity: &Activity, format: Format, mut response: ResponseBuilder) -> ResponseResult {
+    if activity.samples.is_empty() {
+        response.status(StatusCode::NOT_FOUND);
+        return Ok(response.body("<h1>404</h1><p>No activity yet!<p>".as_bytes().to_vec())?);
+    }
+
+    response.header("content-type", format.content_type());
+    response.header("content-disposition", format!("attachment; filename=\"activity.{}\"", format.extension()));
+    response.header("cache-control", "no-cache");
+    Ok(response.body(activity.export(format))?)
+}
+
+/// Index, status and documents of a cache, `uri` is relative to its route `base`.
+fn serve_documents(cache: &Cache, endpoints: &Endpoints, stale_payload: StalePayload, base: &str, uri: &str, request: &Request<Vec<u8>>, mut response: ResponseBuilder) -> ResponseResult {
+    if uri.is_empty() && endpoints.index {
+        response.header("content-type", "text/json");
+        Ok(response.body(cache.index(base).into_bytes())?)
+    } else if uri == "status" && endpoints.status {
+        response.header("content-type", "text/json");
+        Ok(response.body(cache.status().into_bytes())?)
+    } else if uri == "watcher" && endpoints.status {
+        response.header("content-type", "text/json");
+        Ok(response.body(cache.watcher_status().into_bytes())?)
+    } else if let (true, Some(data)) = (endpoints.documents, cache.get_versioned(uri)) {
+        serve_document(cache, uri, data, stale_payload, request, response)
+    } else if let (true, Some(data)) = (endpoints.documents, uri.strip_suffix(".csv").and_then(|name| cache.get(name))) {
+        serve_csv(&data, response)
+    } else {
+        response.status(StatusCode::NOT_FOUND);
+        Ok(response.body("<h1>404</h1><p>File not found!<p>".as_bytes().to_vec())?)
+    }
+}
+
+/// An array-shaped document as CSV table.
+fn serve_csv(raw: &str, mut response: ResponseBuilder) -> ResponseResult {
+    match Table::from_json(raw) {
+        Some(table) => {
+            response.header("content-type", "text/csv; charset=utf-8");
+            response.header("cache-control", "no-cache");
+            Ok(response.body(table.to_csv().into_bytes())?)
+        },
+        None => {
+            response.status(StatusCode::NOT_FOUND);
+            Ok(response.body("<h1>404</h1><p>Document is not a table!<p>".as_bytes().to_vec())?)
+        },
+    }
+}
+
+/// Longest a long-polling request is held open, whatever `wait` it asks for.
+const MAX_WAIT_MS: u64 = 30_000;
+
+/// A document with its change metadata, `304 Not Modified` if the client has it already.
+///
+/// With `?since=<version>` it is only sent once it is newer than that version,
+/// waiting up to `&wait=<ms>` for it to change, unless half of the server's
+/// threads wait already. The age in seconds and whether it is stale are in
+/// the `x-tpv-age` and `x-tpv-stale` headers, a stale document is replaced
+/// by `stale_payload`. The body is shared with the cache, compressed if the
+/// client accepts gzip.
+fn serve_document(cache: &Cache, name: &str, mut data: Arc<Versioned>, stale_payload: StalePayload, request: &Request<Vec<u8>>, mut response: ResponseBuilder) -> ResponseResult {
+    let query = request.uri().query();
+    let since = query_param(query, "since").and_then(|v| v.parse::<u64>().ok());
+
+    if let Some(since) = since {
+        let wait = query_param(query, "wait").and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+        if data.version <= since && wait > 0 {
+            if let Some(newer) = cache.wait_newer(name, since, Duration::from_millis(wait.min(MAX_WAIT_MS))) {
+                data = newer;
+            }
+        }
+    }
+
+    let stale = cache.is_stale(name);
+    response.header("x-tpv-age", cache.age(name).unwrap_or_default().as_secs().to_string().as_str());
+    response.header("x-tpv-stale", stale.to_string().as_str());
+
+    let payload = match stale_payload {
+        _ if !stale => None,
+        StalePayload::Last => None,
+        StalePayload::Empty => Some("[]"),
+        StalePayload::Offline => Some(r#"[{"offline":true}]"#),
+    };
+    if let Some(payload) = payload {
+        // no validators, the client must not keep this once the document is back
+        response.header("content-type", "text/json");
+        response.header("cache-control", "no-cache");
+        return Ok(response.body(payload.as_bytes().to_vec())?);
+    }
+
+    response.header("etag", data.etag.as_str());
+    response.header("last-modified", data.last_modified.as_str());
+    response.header("x-tpv-version", data.version.to_string().as_str());
+    response.header("cache-control", "no-cache");
+    response.header("vary", "accept-encoding");
+
+    if since.is_some_and(|since| data.version <= since) || not_modified(request, &data) {
+        response.status(StatusCode::NOT_MODIFIED);
+        return Ok(response.body(Vec::new())?);
+    }
+
+    response.header("content-type", "text/json");
+    match &data.gzip {
+        Some(gzip) if accepts_gzip(request) => {
+            response.header("content-encoding", "gzip");
+            simple_server::shared(&mut response, gzip.clone())
+        },
+        _ => simple_server::shared(&mut response, data.body()),
+    }
+}
+
+/// Whether `Accept-Encoding` allows gzip, i.e. lists it without `q=0`.
+fn accepts_gzip(request: &Request<Vec<u8>>) -> bool {
+    request.headers().get_all("accept-encoding").iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            params.next().is_some_and(|name| name.eq_ignore_ascii_case("gzip") || name == "*")
+                && params.all(|param| !matches!(param.strip_prefix("q="), Some(q) if q.parse::<f64>().is_ok_and(|q| q == 0.0)))
+        })
+}
+
+/// Whether the conditional headers of a request match the current version.
+///
+/// `If-None-Match` takes precedence, `If-Modified-Since` is only looked at without it.
+fn not_modified(request: &Request<Vec<u8>>, data: &Versioned) -> bool {
+    let headers = request.headers();
+
+    if let Some(tags) = headers.get("if-none-match").and_then(|v| v.to_str().ok()) {
+        return tags.split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == data.etag);
+    }
+
+    match headers.get("if-modified-since").and_then(|v| v.to_str().ok()).map(httpdate::parse_http_date) {
+        // the header only has whole seconds
+        Some(Ok(since)) => data.modified.duration_since(since).map_or(true, |d| d.as_secs() == 0),
+        _ => false,
+    }
+}
+
+/// Value of a parameter from an (undecoded) URI query string.
+pub fn query_param<'a>(query: Option<&'a str>, key: &str) -> Option<&'a str> {
+    query?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+#[cfg(test)]
+mod instance_should {
+    use super::*;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serve_in_background_until_shut_down() {
+        let dir = std::env::temp_dir().join(format!("tpvbc2http-instance-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("focus.json"), r#"[{"name":"A"}]"#).unwrap();
+
+        let handle = Instance::new().start("127.0.0.1", "0", Some(dir.to_str().unwrap().to_string())).unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        assert!(get(handle.local_addr(), "/bcast/focus").ends_with(r#"[{"name":"A"}]"#));
+        assert_eq!(Some(r#"[{"name":"A"}]"#), handle.cache().get("focus").as_deref());
+
+        handle.shutdown();
+        handle.wait();
+        assert!(TcpStream::connect(handle.local_addr()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn record_every_source() {
+        let dir = std::env::temp_dir().join(format!("tpvbc2http-record-sources-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("alice")).unwrap();
+        fs::write(dir.join("alice").join("focus.json"), r#"[{"name":"Alice"}]"#).unwrap();
+
+        let mut instance = Instance::new();
+        instance.add_source("alice", dir.join("alice")).unwrap();
+        instance.set_record_dir(dir.join("journal"), 1 << 20);
+        let handle = instance.start("127.0.0.1", "0", None).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        handle.shutdown();
+        handle.wait();
+
+        let part = fs::read_dir(dir.join("journal").join("alice")).unwrap().next().unwrap().unwrap().path();
+        let records = crate::recorder::read_journal(&part).unwrap();
+        assert!(records.iter().any(|record| record.name == "focus" && record.raw == r#"[{"name":"Alice"}]"#));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn match_conditional_headers() {
+        let cache = Cache::new();
+        cache.update("focus", String::from("[1]"));
+        let data = cache.get_versioned("focus").unwrap();
+
+        let request = |header: &str, value: &str| {
+            Request::builder().uri("/bcast/focus").header(header, value).body(Vec::new()).unwrap()
+        };
+
+        assert!(not_modified(&request("if-none-match", &format!("\"x\", {}", data.etag)), &data));
+        assert!(!not_modified(&request("if-none-match", "\"x\""), &data));
+        assert!(not_modified(&request("if-modified-since", &httpdate::fmt_http_date(data.modified)), &data));
+        assert!(!not_modified(&request("if-modified-since", "Thu, 01 Jan 1970 00:00:00 GMT"), &data));
+    }
+
+    #[test]
+    fn replace_stale_documents() {
+        let cache = Cache::new();
+        cache.update("focus", String::from("[1]"));
+        cache.set_stale_after(StaleAfter { default: Some(Duration::ZERO), ..StaleAfter::default() });
+
+        let request = Request::builder().uri("/bcast/focus").body(Vec::new()).unwrap();
+        let serve = |payload| {
+            let data = cache.get_versioned("focus").unwrap();
+            serve_document(&cache, "focus", data, payload, &request, simple_server::Response::builder()).unwrap()
+        };
+
+        let offline = serve(StalePayload::Offline);
+        assert_eq!("true", offline.headers()["x-tpv-stale"]);
+        assert_eq!("0", offline.headers()["x-tpv-age"]);
+        assert!(offline.headers().get("etag").is_none());
+        assert_eq!(br#"[{"offline":true}]"#.to_vec(), *offline.body());
+
+        let last = serve(StalePayload::Last);
+        assert!(last.headers().get("etag").is_some());
+    }
+
+    #[test]
+    fn serve_compressed_documents_to_clients_accepting_gzip() {
+        use flate2::read::GzDecoder;
+
+        let dir = std::env::temp_dir().join(format!("tpvbc2http-gzip-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let groups = format!("[{}]", vec![r#"{"size":1,"leader":"Joe"}"#; 20].join(","));
+        fs::write(dir.join("groups.json"), &groups).unwrap();
+
+        let handle = Instance::new().start("127.0.0.1", "0", Some(dir.to_str().unwrap().to_string())).unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+        write!(stream, "GET /bcast/groups HTTP/1.1\r\nAccept-Encoding: deflate, gzip;q=0.8\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        assert!(String::from_utf8_lossy(&response[..end]).contains("content-encoding: gzip\r\n"));
+        let mut body = String::new();
+        GzDecoder::new(&response[end..]).read_to_string(&mut body).unwrap();
+        assert_eq!(groups, body);
+
+        assert!(get(handle.local_addr(), "/bcast/groups").ends_with(&groups));
+
+        handle.shutdown();
+        handle.wait();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn negotiate_gzip() {
+        let request = |value: &str| Request::builder().header("accept-encoding", value).body(Vec::new()).unwrap();
+
+        assert!(accepts_gzip(&request("gzip, deflate, br")));
+        assert!(accepts_gzip(&request("*")));
+        assert!(!accepts_gzip(&request("gzip;q=0, identity")));
+        assert!(!accepts_gzip(&request("br")));
+        assert!(!accepts_gzip(&Request::builder().body(Vec::new()).unwrap()));
+    }
+}
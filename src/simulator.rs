@@ -0,0 +1,643 @@
+//! Synthetic broadcast files, for demoing and testing without TPV.
+//!
+//! A simple race is simulated: every rider holds a randomly wandering effort
+//! around their FTP, speed follows from power, gradient and drafting, and
+//! results are taken whenever a rider passes a location. The documents are
+//! written like TPV does, with a BOM and only when their content changed.
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tpvbc2http::model::{Entry, Event, Focus, Group, Nearest, ResultIndv, ResultTeam};
+
+const NAMES: [&str; 12] = [
+    "Anna", "Ben", "Chloe", "David", "Emma", "Felix", "Greta", "Hugo", "Ida", "Jonas", "Kira", "Luca",
+];
+const SURNAMES: [&str; 8] = ["Berger", "Costa", "Dubois", "Evans", "Fischer", "Jensen", "Novak", "Rossi"];
+const COUNTRIES: [&str; 8] = ["AUT", "ITA", "FRA", "GBR", "GER", "DEN", "CZE", "ESP"];
+const TEAMS: [(&str, &str); 4] = [
+    ("Rolling Thunder", "RT"),
+    ("Chain Gang", "CG"),
+    ("Watt Busters", "WB"),
+    ("Draft Dodgers", "DD"),
+];
+
+/// Points for the first riders at a location in points races.
+const POINTS: [i64; 8] = [10, 8, 6, 5, 4, 3, 2, 1];
+
+// rider and bike, for the power to speed model
+const MASS: f64 = 80.0;
+const CRR: f64 = 0.004;
+const CDA: f64 = 0.3;
+const RHO: f64 = 1.2;
+const G: f64 = 9.81;
+
+/// Distance behind another rider within which a rider is drafting, and the saving.
+const DRAFT_DISTANCE: f64 = 3.0;
+const DRAFT_SAVING: f64 = 0.3;
+
+/// Time gap that splits two groups in seconds.
+const GROUP_GAP: f64 = 2.0;
+
+#[derive(clap::Args, Debug)]
+pub struct Options {
+    /// Directory to write the broadcast files to
+    #[arg(short, long)]
+    dir: String,
+
+    /// Updates per second
+    #[arg(long, default_value_t = 1.0)]
+    rate: f64,
+
+    /// Number of riders, the first one is in focus
+    #[arg(long, default_value_t = 20)]
+    riders: usize,
+
+    /// Route distance per lap in meters
+    #[arg(long, default_value_t = 20000.0)]
+    distance: f64,
+
+    /// Number of laps
+    #[arg(long, default_value_t = 1)]
+    laps: i64,
+
+    /// Number of locations with results, the last one is the finish
+    #[arg(long, default_value_t = 3)]
+    locations: i64,
+
+    /// Event type, "Points Race" awards points and "Elimination" eliminates the last rider at each location
+    #[arg(long, default_value_t = String::from("Scratch Race"))]
+    event_type: String,
+
+    /// Seed for the random numbers, taken from the clock if not given
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+/// Small xorshift generator, good enough for wobbly riders.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform in [-1, 1).
+    fn signed(&mut self) -> f64 {
+        self.next() * 2.0 - 1.0
+    }
+}
+
+struct Rider {
+    name: String,
+    country: String,
+    team: (&'static str, &'static str),
+    ftp: f64,
+    effort: f64,
+    power: f64,
+    cadence: f64,
+    heartrate: f64,
+    speed: f64,
+    distance: f64,
+    draft: bool,
+    eliminated: bool,
+    finished: bool,
+    // for the focus document
+    power_sum: f64,
+    power_max: f64,
+    cadence_sum: f64,
+    cadence_max: f64,
+    heartrate_sum: f64,
+    heartrate_max: f64,
+    rolling: Vec<f64>,
+    nrm_sum: f64,
+    samples: f64,
+}
+
+impl Rider {
+    fn new(index: usize, rng: &mut Rng) -> Rider {
+        Rider {
+            name: format!("{} {}", NAMES[index % NAMES.len()], SURNAMES[(index / NAMES.len() + index) % SURNAMES.len()]),
+            country: COUNTRIES[index % COUNTRIES.len()].to_string(),
+            team: TEAMS[index % TEAMS.len()],
+            ftp: 220.0 + rng.next() * 130.0,
+            effort: 0.9,
+            power: 0.0,
+            cadence: 0.0,
+            heartrate: 70.0,
+            speed: 0.0,
+            distance: 0.0,
+            draft: false,
+            eliminated: false,
+            finished: false,
+            power_sum: 0.0,
+            power_max: 0.0,
+            cadence_sum: 0.0,
+            cadence_max: 0.0,
+            heartrate_sum: 0.0,
+            heartrate_max: 0.0,
+            rolling: Vec::new(),
+            nrm_sum: 0.0,
+            samples: 0.0,
+        }
+    }
+
+    fn riding(&self) -> bool {
+        !self.eliminated && !self.finished
+    }
+}
+
+/// Height of the route in meters.
+fn height(distance: f64) -> f64 {
+    120.0 + 25.0 * (2.0 * PI * distance / 6000.0).sin() + 8.0 * (2.0 * PI * distance / 2300.0).sin()
+}
+
+fn slope(distance: f64) -> f64 {
+    height(distance + 1.0) - height(distance)
+}
+
+/// Speed in m/s at which `power` is needed, found by bisection.
+fn speed_for(power: f64, slope: f64, draft: bool) -> f64 {
+    let drag = if draft { 1.0 - DRAFT_SAVING } else { 1.0 };
+    let needed = |v: f64| (CRR * MASS * G + MASS * G * slope) * v + 0.5 * RHO * CDA * drag * v * v * v;
+
+    let (mut lo, mut hi) = (0.0, 30.0);
+    for _ in 0..40 {
+        let mid = (lo + hi) / 2.0;
+        if needed(mid) < power {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    // some minimum speed, nobody stops on a climb
+    lo.max(1.5)
+}
+
+struct Simulation {
+    options: Options,
+    rng: Rng,
+    riders: Vec<Rider>,
+    time: f64,
+    results_indv: Vec<ResultIndv>,
+    /// Next location index for every rider.
+    next_location: Vec<i64>,
+    points: Vec<i64>,
+    written: HashMap<&'static str, String>,
+}
+
+impl Simulation {
+    fn new(options: Options) -> Simulation {
+        let seed = options.seed.unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(1)
+        });
+        let mut rng = Rng(seed.max(1));
+        let riders: Vec<Rider> = (0..options.riders.max(1)).map(|i| Rider::new(i, &mut rng)).collect();
+        let count = riders.len();
+
+        Simulation {
+            options,
+            rng,
+            riders,
+            time: 0.0,
+            results_indv: Vec::new(),
+            next_location: vec![1; count],
+            points: vec![0; count],
+            written: HashMap::new(),
+        }
+    }
+
+    fn total_distance(&self) -> f64 {
+        self.options.distance * self.options.laps as f64
+    }
+
+    fn location_distance(&self, location: i64) -> f64 {
+        self.total_distance() * location as f64 / self.options.locations.max(1) as f64
+    }
+
+    fn step(&mut self, dt: f64) {
+        self.time += dt;
+
+        // who is drafting behind whom, based on the positions before moving
+        let positions: Vec<(f64, bool)> = self.riders.iter().map(|r| (r.distance, r.riding())).collect();
+
+        for (i, rider) in self.riders.iter_mut().enumerate() {
+            if !rider.riding() {
+                rider.power = 0.0;
+                rider.cadence = 0.0;
+                rider.speed = 0.0;
+                continue;
+            }
+
+            rider.draft = positions.iter().enumerate().any(|(j, (d, riding))| {
+                j != i && *riding && *d > rider.distance && *d - rider.distance < DRAFT_DISTANCE
+            });
+
+            // effort wanders around threshold, with the occasional attack
+            rider.effort = (rider.effort + self.rng.signed() * 0.03 * dt).clamp(0.7, 1.1);
+            let attack = if self.rng.next() < 0.002 * dt { 0.5 } else { 0.0 };
+            let target = rider.ftp * (rider.effort + attack);
+            rider.power = (target + self.rng.signed() * 15.0).max(0.0);
+            rider.cadence = (88.0 + self.rng.signed() * 6.0).max(0.0);
+            let hr_target = 80.0 + 100.0 * rider.power / rider.ftp;
+            rider.heartrate += (hr_target - rider.heartrate) * (0.05 * dt).min(1.0);
+
+            rider.speed = speed_for(rider.power, slope(rider.distance), rider.draft);
+            rider.distance += rider.speed * dt;
+
+            rider.samples += 1.0;
+            rider.power_sum += rider.power;
+            rider.power_max = rider.power_max.max(rider.power);
+            rider.cadence_sum += rider.cadence;
+            rider.cadence_max = rider.cadence_max.max(rider.cadence);
+            rider.heartrate_sum += rider.heartrate;
+            rider.heartrate_max = rider.heartrate_max.max(rider.heartrate);
+
+            // normalized power from a 30s rolling average
+            let window = (30.0 / dt).round().max(1.0) as usize;
+            rider.rolling.push(rider.power);
+            if rider.rolling.len() > window {
+                rider.rolling.remove(0);
+            }
+            let rolling = rider.rolling.iter().sum::<f64>() / rider.rolling.len() as f64;
+            rider.nrm_sum += rolling.powi(4);
+        }
+
+        self.take_results();
+    }
+
+    fn take_results(&mut self) {
+        let points_race = self.options.event_type.to_lowercase().contains("points");
+        let elimination = self.options.event_type.to_lowercase().contains("elimination");
+
+        // riders passing a location, in the order they passed it
+        let mut passing: Vec<(usize, i64)> = Vec::new();
+        for (i, rider) in self.riders.iter().enumerate() {
+            let location = self.next_location[i];
+            if rider.riding() && location <= self.options.locations && rider.distance >= self.location_distance(location) {
+                passing.push((i, location));
+            }
+        }
+        passing.sort_by(|a, b| {
+            let over = |&(i, l): &(usize, i64)| self.riders[i].distance - self.location_distance(l);
+            over(b).total_cmp(&over(a))
+        });
+
+        for (i, location) in passing {
+            let position = self.results_indv.iter().filter(|r| r.location == location).count() as i64 + 1;
+            let points = if points_race { POINTS.get(position as usize - 1).copied().unwrap_or(0) } else { 0 };
+            self.points[i] += points;
+
+            // time at which the location was actually passed
+            let rider = &self.riders[i];
+            let over = (rider.distance - self.location_distance(location)) / rider.speed.max(0.1);
+            let time = ((self.time - over) * 1000.0).round();
+            let first = self.results_indv.iter().find(|r| r.location == location).map(|r| r.time).unwrap_or(time);
+
+            self.results_indv.push(ResultIndv {
+                location,
+                position,
+                name: rider.name.clone(),
+                country: rider.country.clone(),
+                team: rider.team.0.to_string(),
+                team_code: rider.team.1.to_string(),
+                points,
+                points_total: self.points[i],
+                time,
+                delta_time: time - first,
+                is_eliminated: false,
+                ..Default::default()
+            });
+
+            self.next_location[i] += 1;
+            if location == self.options.locations {
+                self.riders[i].finished = true;
+            }
+        }
+
+        if elimination {
+            self.eliminate();
+        }
+    }
+
+    // the last rider is eliminated once everybody else passed a location (but not the finish)
+    fn eliminate(&mut self) {
+        let riding: Vec<usize> = (0..self.riders.len()).filter(|&i| self.riders[i].riding()).collect();
+        if riding.len() < 2 {
+            return;
+        }
+
+        let location = riding.iter().map(|&i| self.next_location[i]).min().unwrap_or(1);
+        let behind: Vec<usize> = riding.iter().copied().filter(|&i| self.next_location[i] == location).collect();
+
+        if location < self.options.locations && behind.len() == 1 {
+            let last = behind[0];
+            self.riders[last].eliminated = true;
+            let name = self.riders[last].name.clone();
+
+            for result in self.results_indv.iter_mut().filter(|r| r.name == name) {
+                result.is_eliminated = true;
+            }
+        }
+    }
+
+    /// Position of every rider, by locations passed and distance.
+    fn ranking(&self) -> Vec<usize> {
+        let mut ranking: Vec<usize> = (0..self.riders.len()).collect();
+        ranking.sort_by(|&a, &b| {
+            let key = |i: usize| (self.riders[i].eliminated, -(self.next_location[i]), -self.riders[i].distance);
+            let (ea, la, da) = key(a);
+            let (eb, lb, db) = key(b);
+            ea.cmp(&eb).then(la.cmp(&lb)).then(da.total_cmp(&db))
+        });
+        ranking
+    }
+
+    fn focus(&self) -> Vec<Focus> {
+        let rider = &self.riders[0];
+        let samples = rider.samples.max(1.0);
+        let nrm_power = (rider.nrm_sum / samples).powf(0.25);
+        let intensity = nrm_power / rider.ftp;
+        let position = self.ranking().iter().position(|&i| i == 0).unwrap_or(0) as i64 + 1;
+        let next_location = self.next_location[0].min(self.options.locations);
+
+        vec![Focus {
+            name: rider.name.clone(),
+            country: rider.country.clone(),
+            team: rider.team.0.to_string(),
+            team_code: rider.team.1.to_string(),
+            power: rider.power.round(),
+            avg_power: (rider.power_sum / samples).round(),
+            nrm_power: nrm_power.round(),
+            max_power: rider.power_max.round(),
+            cadence: rider.cadence.round(),
+            avg_cadence: (rider.cadence_sum / samples).round(),
+            max_cadence: rider.cadence_max.round(),
+            heartrate: rider.heartrate.round(),
+            avg_heartrate: (rider.heartrate_sum / samples).round(),
+            max_heartrate: rider.heartrate_max.round(),
+            time: self.time.floor(),
+            distance: rider.distance.round(),
+            height: height(rider.distance).round(),
+            speed: (rider.speed * 1000.0).round(),
+            tss: (self.time * nrm_power * intensity / (rider.ftp * 3600.0) * 100.0).round(),
+            calories: (rider.power_sum / samples * self.time / 1000.0).round(),
+            draft: if rider.draft { DRAFT_SAVING * 100.0 } else { 0.0 },
+            wind_speed: 0.0,
+            wind_angle: 0.0,
+            slope: (slope(rider.distance) * 1000.0).round() / 10.0,
+            event_laps_total: self.options.laps,
+            event_laps_done: ((rider.distance / self.options.distance) as i64).min(self.options.laps),
+            event_distance_total: self.total_distance().round(),
+            event_distance_done: rider.distance.min(self.total_distance()).round(),
+            event_distance_to_next_location: (self.location_distance(next_location) - rider.distance).max(0.0).round(),
+            event_next_location: next_location,
+            event_position: position,
+            ..Default::default()
+        }]
+    }
+
+    fn nearest(&self) -> Vec<Nearest> {
+        let ranking = self.ranking();
+        let focus = ranking.iter().position(|&i| i == 0).unwrap_or(0);
+        let from = focus.saturating_sub(2);
+        let to = (focus + 3).min(ranking.len());
+        let me = &self.riders[0];
+
+        ranking[from..to].iter().map(|&i| {
+            let rider = &self.riders[i];
+            let gap = (me.distance - rider.distance) / me.speed.max(rider.speed).max(1.0);
+
+            Nearest {
+                name: rider.name.clone(),
+                country: rider.country.clone(),
+                team: rider.team.0.to_string(),
+                team_code: rider.team.1.to_string(),
+                speed: (rider.speed * 1000.0).round(),
+                time_gap: (gap * 1000.0).round(),
+                position: ranking.iter().position(|&r| r == i).unwrap_or(0) as i64 + 1,
+                distance: rider.distance.round(),
+                is_eliminated: rider.eliminated,
+                ..Default::default()
+            }
+        }).collect()
+    }
+
+    fn entries(&self) -> Vec<Entry> {
+        self.riders.iter().enumerate().map(|(i, rider)| Entry {
+            bib_num: i as i64 + 1,
+            name: rider.name.clone(),
+            country: rider.country.clone(),
+            team: rider.team.0.to_string(),
+            team_code: rider.team.1.to_string(),
+            ..Default::default()
+        }).collect()
+    }
+
+    fn event(&self) -> Vec<Event> {
+        vec![Event {
+            name: String::from("Simulated Event"),
+            route: String::from("Sine Valley"),
+            laps: self.options.laps,
+            distance: self.total_distance().round(),
+            height: (50.0 * self.total_distance() / 6000.0).round(),
+            locations: self.options.locations,
+            kind: self.options.event_type.clone(),
+            ..Default::default()
+        }]
+    }
+
+    fn groups(&self) -> Vec<Group> {
+        let riding: Vec<usize> = self.ranking().into_iter().filter(|&i| self.riders[i].riding()).collect();
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+
+        for i in riding {
+            let split = match groups.last().and_then(|g| g.last()) {
+                Some(&ahead) => {
+                    let gap = self.riders[ahead].distance - self.riders[i].distance;
+                    gap / self.riders[i].speed.max(1.0) > GROUP_GAP
+                },
+                None => true,
+            };
+            if split {
+                groups.push(vec![i]);
+            } else {
+                groups.last_mut().unwrap().push(i);
+            }
+        }
+
+        let largest = groups.iter().map(Vec::len).max().unwrap_or(0);
+        let head = groups.first().and_then(|g| g.first()).map(|&i| self.riders[i].distance).unwrap_or(0.0);
+        let mut peloton_seen = false;
+
+        groups.iter().enumerate().map(|(n, group)| {
+            let leader = &self.riders[group[0]];
+            let is_peloton = !peloton_seen && group.len() == largest && largest > 1;
+            peloton_seen |= is_peloton;
+
+            Group {
+                group_num1: n as i64 + 1,
+                group_num2: 0,
+                leader: Some(leader.name.clone()),
+                size: group.len() as i64,
+                time_gap1: ((head - leader.distance) / leader.speed.max(1.0) * 1000.0).round(),
+                time_gap2: 0.0,
+                is_peloton,
+                ..Default::default()
+            }
+        }).collect()
+    }
+
+    fn results_team(&self) -> Vec<ResultTeam> {
+        let mut results = Vec::new();
+
+        for location in 1..=self.options.locations {
+            let mut teams: Vec<ResultTeam> = Vec::new();
+
+            for result in self.results_indv.iter().filter(|r| r.location == location) {
+                match teams.iter_mut().find(|t| t.team_code == result.team_code) {
+                    Some(team) => team.points_total += result.points_total,
+                    None => teams.push(ResultTeam {
+                        location,
+                        team: result.team.clone(),
+                        team_code: result.team_code.clone(),
+                        points_total: result.points_total,
+                        time: result.time,
+                        ..Default::default()
+                    }),
+                }
+            }
+
+            // teams are in the order of their best rider, which is the time order
+            let first = teams.first().map(|t| t.time).unwrap_or(0.0);
+            for (n, team) in teams.iter_mut().enumerate() {
+                team.position = n as i64 + 1;
+                team.delta_time = team.time - first;
+            }
+            results.extend(teams);
+        }
+        results
+    }
+
+    /// Write a document if its content changed, with BOM like TPV.
+    fn write<T: serde::Serialize>(&mut self, dir: &Path, name: &'static str, records: &[T], pretty: bool) -> io::Result<()> {
+        let content = if pretty {
+            serde_json::to_string_pretty(records)?
+        } else {
+            serde_json::to_string(records)?
+        };
+
+        if self.written.get(name) != Some(&content) {
+            fs::write(dir.join(format!("{}.json", name)), format!("\u{feff}{}", content))?;
+            self.written.insert(name, content);
+        }
+        Ok(())
+    }
+
+    fn write_all(&mut self, dir: &Path) -> io::Result<()> {
+        let (focus, nearest, entries, event) = (self.focus(), self.nearest(), self.entries(), self.event());
+        let (groups, results_indv, results_team) = (self.groups(), self.results_indv.clone(), self.results_team());
+
+        self.write(dir, "focus", &focus, false)?;
+        self.write(dir, "nearest", &nearest, true)?;
+        self.write(dir, "entries", &entries, true)?;
+        self.write(dir, "event", &event, true)?;
+        self.write(dir, "groups", &groups, true)?;
+        self.write(dir, "resultsIndv", &results_indv, true)?;
+        self.write(dir, "resultsTeam", &results_team, true)
+    }
+}
+
+/// Run the simulation until the process is stopped.
+pub fn run(options: Options) -> io::Result<()> {
+    if !(options.rate.is_finite() && options.rate > 0.0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "rate must be a finite number greater than 0"));
+    }
+
+    let dir = PathBuf::from(&options.dir);
+    fs::create_dir_all(&dir)?;
+
+    let dt = 1.0 / options.rate;
+    log::info!(
+        "Simulating {} riders, {} x {}m {} into {}",
+        options.riders, options.laps, options.distance, options.event_type, dir.display(),
+    );
+
+    let mut simulation = Simulation::new(options);
+    let mut finished = false;
+
+    loop {
+        simulation.step(dt);
+        simulation.write_all(&dir)?;
+
+        if !finished && simulation.riders.iter().all(|r| !r.riding()) {
+            log::info!("Simulated event finished after {:.0}s", simulation.time);
+            finished = true;
+        }
+
+        thread::sleep(Duration::from_secs_f64(dt));
+    }
+}
+
+#[cfg(test)]
+mod simulator_should {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        options: Options,
+    }
+
+    fn simulation(args: &[&str]) -> Simulation {
+        let mut argv = vec!["simulate", "--dir", "unused", "--seed", "42"];
+        argv.extend_from_slice(args);
+        Simulation::new(Cli::parse_from(argv).options)
+    }
+
+    #[test]
+    fn finish_race_with_results_at_every_location() {
+        let mut sim = simulation(&["--riders", "5", "--distance", "2000", "--event-type", "Points Race"]);
+
+        for _ in 0..2000 {
+            sim.step(1.0);
+        }
+
+        assert!(sim.riders.iter().all(|r| r.finished));
+        assert_eq!(15, sim.results_indv.len());
+        assert_eq!(10, sim.results_indv[0].points);
+        assert_eq!(1, sim.focus()[0].event_laps_done);
+
+        let teams = sim.results_team();
+        assert_eq!(12, teams.len());
+        assert_eq!((1, 0.0), (teams[0].position, teams[0].delta_time));
+    }
+
+    #[test]
+    fn eliminate_last_rider_at_each_location() {
+        let mut sim = simulation(&["--riders", "4", "--distance", "3000", "--event-type", "Elimination"]);
+
+        for _ in 0..2000 {
+            sim.step(1.0);
+        }
+
+        // eliminated at the first two locations, the finish decides the rest
+        assert_eq!(2, sim.riders.iter().filter(|r| r.eliminated).count());
+    }
+
+    #[test]
+    fn reject_rates_which_are_not_positive_and_finite() {
+        for rate in ["0", "-1", "NaN", "inf"] {
+            let options = Cli::parse_from(["simulate", "--dir", "unused", &format!("--rate={}", rate)]).options;
+            assert_eq!(io::ErrorKind::InvalidInput, run(options).unwrap_err().kind());
+        }
+    }
+}
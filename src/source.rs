@@ -0,0 +1,668 @@
+//! Watching the TPV broadcast directory.
+//!
+//! A file is reloaded once TPV is done writing it: when it is closed after
+//! writing or renamed into place. Further events within the debounce window
+//! are coalesced into one reload, and a file whose content did not change
+//! since it was last loaded is not loaded again. The periodic rescan and the
+//! polling watcher skip files whose size and modification time did not
+//! change, without reading them. Events are not skipped that way: some mounts
+//! keep the modification time in whole seconds.
+//!
+//! Network shares often deliver no events at all, the directory can be
+//! polled instead (see `WatcherBackend`). A missing directory is waited for,
+//! and watched again when it is removed and created anew.
+
+use unicode_bom::Bom;
+use std::{fs, io, thread};
+use std::collections::{BTreeSet, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+use notify::{Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use crate::cache::{Cache, Reload, WatcherState};
+
+/// Delays between attempts to reload a broadcast file which could not be read or parsed.
+const RELOAD_BACKOFF_MS: [u64; 4] = [10, 20, 40, 80];
+
+/// How often the directory is checked for being removed or (re)created.
+const DIR_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Default time to wait for further events of a file before reloading it.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// What the watcher thread waits for.
+enum Signal {
+    Event(notify::Result<Event>),
+    Stop,
+}
+
+/// How a broadcast directory is watched.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatcherBackend {
+    /// The file system events of the platform: inotify, FSEvents or ReadDirectoryChangesW.
+    Native,
+    /// Comparing the directory every interval, works on network shares too.
+    Polling(Duration),
+    /// Native, but polling every `poll_interval` once no events arrived for
+    /// `fallback` (or if the native watcher can't be started).
+    Auto { poll_interval: Duration, fallback: Duration },
+}
+
+/// A broadcast directory, feeding the files in it into a cache.
+pub struct BroadcastSource {
+    dir: PathBuf,
+    rescan_interval: Option<Duration>,
+    debounce: Duration,
+    backend: WatcherBackend,
+}
+
+/// A broadcast file as it was last loaded.
+struct Fingerprint {
+    len: u64,
+    modified: Option<SystemTime>,
+    hash: u64,
+}
+
+/// A running `BroadcastSource`, stopped when dropped.
+pub struct SourceHandle {
+    tx: mpsc::Sender<Signal>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl BroadcastSource {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> BroadcastSource {
+        BroadcastSource {
+            dir: dir.into(),
+            rescan_interval: None,
+            debounce: DEFAULT_DEBOUNCE,
+            backend: WatcherBackend::Native,
+        }
+    }
+
+    /// How the directory is watched, `WatcherBackend::Native` by default.
+    pub fn set_watcher(&mut self, backend: WatcherBackend) {
+        self.backend = backend;
+    }
+
+    /// Time to wait for further events of a file before reloading it, so a
+    /// burst of writes results in a single reload. Zero reloads right away.
+    pub fn set_debounce(&mut self, debounce: Duration) {
+        self.debounce = debounce;
+    }
+
+    /// Periodically reload all broadcast files, in case the watcher misses an event.
+    ///
+    /// Disabled by default, pass `None` to disable it again.
+    pub fn set_rescan_interval(&mut self, interval: Option<Duration>) {
+        self.rescan_interval = interval;
+    }
+
+    fn read_from_fs(fname: &Path) -> io::Result<String> {
+        match fs::read_to_string(fname) {
+            Ok(conten) => {
+                // if there is a leading BOM, remove it ...
+                let bom = Bom::from(conten.as_bytes());
+                Ok(conten[bom.len()..].to_string())
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reload a single broadcast file into the cache (if it is exposed by the mapping).
+    ///
+    /// TPV might be in the middle of writing the file, so reading is retried a few
+    /// times before giving up and keeping the last good content.
+    pub fn load_file(cache: &Cache, p: &Path) {
+        BroadcastSource::load(cache, p, None);
+    }
+
+    /// Like `load_file`, but the cache is left alone if the content hashes
+    /// to `last_hash`. The hash of the content loaded, `None` if it failed.
+    fn load(cache: &Cache, p: &Path, last_hash: Option<u64>) -> Option<u64> {
+        let name = cache.name_for(p)?;
+        let mut backoff = RELOAD_BACKOFF_MS.iter();
+
+        loop {
+            let (error, invalid) = match BroadcastSource::read_from_fs(p) {
+                Ok(content) => {
+                    let mut hasher = DefaultHasher::new();
+                    content.hash(&mut hasher);
+                    let hash = hasher.finish();
+
+                    // TPV wrote it, but with the same content
+                    if last_hash == Some(hash) {
+                        log::debug!("Content of {} unchanged", p.display());
+                        cache.seen(&name);
+                        return Some(hash);
+                    }
+                    match cache.update(&name, content) {
+                        Reload::Updated(_) | Reload::Unchanged => return Some(hash),
+                        Reload::Invalid(e) => (format!("invalid content: {}", e), true),
+                    }
+                },
+                // this is usually windows complaining about file being open in other process
+                Err(e) => (format!("read failed: {}", e), false),
+            };
+
+            match backoff.next() {
+                Some(ms) => thread::sleep(Duration::from_millis(*ms)),
+                None => {
+                    log::warn!("Keeping last good {} data, {}", name, error);
+                    if invalid {
+                        cache.invalid(&name, error);
+                    } else {
+                        cache.failed(&name, error);
+                    }
+                    return None;
+                },
+            }
+        }
+    }
+
+    /// Reload a file, unless its content is the same as when it was last
+    /// loaded. With `check_metadata`, it isn't even read if its size and
+    /// modification time are the same.
+    fn reload(cache: &Cache, fingerprints: &mut HashMap<PathBuf, Fingerprint>, p: &Path, check_metadata: bool) {
+        let (len, modified) = match fs::metadata(p) {
+            Ok(meta) if meta.is_file() => (meta.len(), meta.modified().ok()),
+            _ => return,
+        };
+
+        let last = fingerprints.get(p);
+        if check_metadata && last.is_some_and(|last| last.len == len && last.modified == modified) {
+            log::debug!("{} unchanged", p.display());
+            return;
+        }
+
+        if let Some(hash) = BroadcastSource::load(cache, p, last.map(|last| last.hash)) {
+            fingerprints.insert(p.to_path_buf(), Fingerprint { len, modified, hash });
+        }
+    }
+
+    /// Every mapped broadcast file in the directory, and with auto mapping
+    /// every other JSON file too.
+    fn files(&self, cache: &Cache) -> BTreeSet<PathBuf> {
+        let mut files: BTreeSet<PathBuf> = cache.mapping().documents.values()
+            .map(|file| self.dir.join(file))
+            .collect();
+
+        if cache.mapping().auto {
+            if let Ok(entries) = fs::read_dir(&self.dir) {
+                files.extend(entries.flatten()
+                    .map(|entry| entry.path())
+                    .filter(|p| p.extension().is_some_and(|ext| ext == "json")));
+            }
+        }
+
+        files
+    }
+
+    /// Load every mapped broadcast file from the directory, and with auto
+    /// mapping every other JSON file too.
+    pub fn rescan(&self, cache: &Cache) {
+        for p in self.files(cache) {
+            if p.is_file() {
+                BroadcastSource::load_file(cache, &p);
+            }
+        }
+    }
+
+    /// Files to reload for a watcher event.
+    ///
+    /// On Linux, files are reloaded when closed after writing or renamed into
+    /// place, not on access: reading a file is an access too, so reloading
+    /// would trigger the next reload. Other platforms and the polling watcher
+    /// have no close events, any change of the content or name counts there.
+    fn changed_files(event: &Event, polling: bool) -> &[PathBuf] {
+        match event.kind {
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) => &event.paths,
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => &event.paths,
+            // from and to
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => &event.paths[event.paths.len().min(1)..],
+            EventKind::Create(_) | EventKind::Modify(_) if polling || !cfg!(target_os = "linux") => &event.paths,
+            _ => &[],
+        }
+    }
+
+    /// Whether an event is about the directory itself being removed or renamed.
+    fn dir_changed(&self, event: &Event) -> bool {
+        matches!(event.kind, EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_)))
+            && event.paths.contains(&self.dir)
+    }
+
+    /// Watch the directory, sending the events to `tx`. Polls if `poll_interval` is given.
+    fn watcher(&self, cache: &Cache, tx: &mpsc::Sender<Signal>, poll_interval: Option<Duration>) -> notify::Result<Box<dyn Watcher + Send>> {
+        let events = tx.clone();
+        let handler = move |res: notify::Result<Event>| {
+            let _ = events.send(Signal::Event(res));
+        };
+
+        let mut watcher: Box<dyn Watcher + Send> = match poll_interval {
+            Some(interval) => Box::new(PollWatcher::new(handler, notify::Config::default().with_poll_interval(interval))?),
+            None => Box::new(RecommendedWatcher::new(handler, notify::Config::default())?),
+        };
+        watcher.watch(&self.dir, RecursiveMode::NonRecursive)?;
+
+        let backend = match poll_interval {
+            Some(interval) => {
+                log::info!("Watching {} by polling every {}ms", self.dir.display(), interval.as_millis());
+                String::from("polling")
+            },
+            None => {
+                log::info!("Watching {} with {:?}", self.dir.display(), RecommendedWatcher::kind());
+                format!("{:?}", RecommendedWatcher::kind()).to_lowercase()
+            },
+        };
+        cache.set_watcher_state(&self.dir, WatcherState::Watching, Some(backend));
+        Ok(watcher)
+    }
+
+    /// Watch the directory with the configured backend, and whether it is polled.
+    fn attach(&self, cache: &Cache, tx: &mpsc::Sender<Signal>) -> notify::Result<(Box<dyn Watcher + Send>, bool)> {
+        match self.backend {
+            WatcherBackend::Native => Ok((self.watcher(cache, tx, None)?, false)),
+            WatcherBackend::Polling(interval) => Ok((self.watcher(cache, tx, Some(interval))?, true)),
+            WatcherBackend::Auto { poll_interval, .. } => match self.watcher(cache, tx, None) {
+                Ok(watcher) => Ok((watcher, false)),
+                Err(e) => {
+                    log::warn!("Native watcher failed on {}: {}, polling instead", self.dir.display(), e);
+                    Ok((self.watcher(cache, tx, Some(poll_interval))?, true))
+                },
+            },
+        }
+    }
+
+    /// Load the directory into `cache` and keep it up to date from a watcher thread.
+    ///
+    /// The files already in the directory are loaded before returning.
+    /// If the directory does not exist (yet), e.g. because TPV was never
+    /// started, it is waited for. When it is removed, the last content is
+    /// kept until it shows up again.
+    pub fn start(self, cache: &Cache) -> notify::Result<SourceHandle> {
+        let (tx, rx) = mpsc::channel::<Signal>();
+
+        let attached = if self.dir.is_dir() {
+            Some(self.attach(cache, &tx)?)
+        } else {
+            log::warn!("{} does not exist, waiting for it", self.dir.display());
+            cache.set_watcher_state(&self.dir, WatcherState::Waiting, None);
+            None
+        };
+        let events = tx.clone();
+
+        // prime the cache with whatever TPV left in the directory
+        let mut fingerprints = HashMap::new();
+        for p in self.files(cache) {
+            BroadcastSource::reload(cache, &mut fingerprints, &p, true);
+        }
+
+        let cache = cache.clone();
+        let thread = thread::spawn(move || {
+            log::info!("Cache started on {}", self.dir.display());
+
+            let (mut watcher, mut polling) = match attached {
+                Some((watcher, polling)) => (Some(watcher), polling),
+                None => (None, false),
+            };
+            // files with events, reloaded once the debounce window is over
+            let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+
+            let mut last_rescan = Instant::now();
+            let mut last_event = Instant::now();
+            let mut last_dir_check = Instant::now();
+            let mut dir_changed = false;
+
+            loop {
+                let next_rescan = self.rescan_interval.map(|interval| last_rescan + interval);
+                let fallback = match self.backend {
+                    WatcherBackend::Auto { poll_interval, fallback } if watcher.is_some() && !polling => Some((poll_interval, last_event + fallback)),
+                    _ => None,
+                };
+                let deadline = pending.values().copied()
+                    .chain(next_rescan)
+                    .chain(fallback.map(|(_, at)| at))
+                    .chain(Some(last_dir_check + DIR_CHECK_INTERVAL))
+                    .min();
+
+                let signal = match deadline {
+                    Some(deadline) => match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(signal) => Some(signal),
+                        Err(mpsc::RecvTimeoutError::Timeout) => None,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    },
+                    None => match rx.recv() {
+                        Ok(signal) => Some(signal),
+                        Err(_) => break,
+                    },
+                };
+
+                let res = match signal {
+                    Some(Signal::Event(res)) => Some(res),
+                    Some(Signal::Stop) => break,
+                    None => None,
+                };
+
+                if res.is_some() {
+                    last_event = Instant::now();
+                }
+
+                match res {
+                    Some(Ok(e)) => {
+                        log::debug!("event: {:?}", e);
+                        dir_changed |= self.dir_changed(&e);
+                        for p in BroadcastSource::changed_files(&e, polling) {
+                            pending.entry(p.clone()).or_insert_with(|| Instant::now() + self.debounce);
+                        }
+                    },
+                    Some(Err(e)) => {
+                        log::warn!("watch error: {:?}", e);
+                        cache.watch_failed(e.to_string());
+                    },
+                    None => (),
+                }
+
+                let now = Instant::now();
+                if let Some((poll_interval, _)) = fallback.filter(|(_, at)| *at <= now) {
+                    log::warn!("No events from the native watcher on {} for {}s, polling instead",
+                        self.dir.display(), now.duration_since(last_event).as_secs());
+                    match self.watcher(&cache, &events, Some(poll_interval)) {
+                        Ok(poller) => {
+                            watcher = Some(poller);
+                            polling = true;
+                        },
+                        Err(e) => {
+                            log::warn!("Polling {} failed: {}", self.dir.display(), e);
+                            cache.watch_failed(e.to_string());
+                            last_event = now;
+                        },
+                    }
+                }
+
+                // the directory went away or was replaced, a watcher on it gets no further events
+                if dir_changed || last_dir_check + DIR_CHECK_INTERVAL <= now {
+                    let exists = self.dir.is_dir();
+                    if watcher.is_some() && (dir_changed || !exists) {
+                        log::warn!("{} was removed, waiting for it", self.dir.display());
+                        watcher = None;
+                        cache.set_watcher_state(&self.dir, WatcherState::Waiting, None);
+                    }
+                    if watcher.is_none() && exists {
+                        match self.attach(&cache, &events) {
+                            Ok((attached, attached_polling)) => {
+                                watcher = Some(attached);
+                                polling = attached_polling;
+                                last_event = now;
+                                // everything in it is new
+                                fingerprints.clear();
+                                pending.extend(self.files(&cache).into_iter().map(|p| (p, now)));
+                            },
+                            Err(e) => {
+                                log::warn!("Watching {} failed: {}", self.dir.display(), e);
+                                cache.watch_failed(e.to_string());
+                            },
+                        }
+                    }
+                    dir_changed = false;
+                    last_dir_check = now;
+                }
+
+                let due: Vec<PathBuf> = pending.iter()
+                    .filter(|(_, due)| **due <= now)
+                    .map(|(p, _)| p.clone())
+                    .collect();
+                for p in due {
+                    pending.remove(&p);
+                    BroadcastSource::reload(&cache, &mut fingerprints, &p, polling);
+                }
+
+                // fallback in case the watcher missed an event
+                if next_rescan.is_some_and(|next| next <= now) {
+                    log::debug!("Periodic rescan of {}", self.dir.display());
+                    for p in self.files(&cache) {
+                        BroadcastSource::reload(&cache, &mut fingerprints, &p, true);
+                    }
+                    last_rescan = Instant::now();
+                }
+            }
+
+            drop(watcher);
+            cache.set_watcher_state(&self.dir, WatcherState::Stopped, None);
+            log::info!("Cache stopped on {}", self.dir.display());
+        });
+
+        Ok(SourceHandle { tx, thread: Some(thread) })
+    }
+}
+
+impl SourceHandle {
+    /// Stop watching and wait for the watcher thread to finish.
+    pub fn stop(mut self) {
+        self.join();
+    }
+
+    fn join(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = self.tx.send(Signal::Stop);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for SourceHandle {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+#[cfg(test)]
+mod source_should {
+    use super::*;
+    use crate::cache::StaleAfter;
+
+    #[test]
+    fn read_a_file_once_per_write() {
+        let dir = std::env::temp_dir().join(format!("tpvbc2http-source-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let cache = Cache::new();
+        let mut source = BroadcastSource::new(&dir);
+        source.set_debounce(Duration::from_millis(20));
+        let handle = source.start(&cache).unwrap();
+        // every file read which did not fail counts as a write
+        let writes = |cache: &Cache| cache.counters().into_iter().find(|counters| counters.name == "focus").unwrap().writes;
+
+        let focus = dir.join("focus.json");
+        fs::write(&focus, r#"[{"name":"Joe","power":200}]"#).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(1, writes(&cache));
+
+        // our own reads must not trigger further reloads
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(1, writes(&cache));
+        assert!(cache.age("focus").unwrap() >= Duration::from_millis(300));
+
+        fs::write(&focus, r#"[{"name":"Joe","power":300}]"#).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(2, writes(&cache));
+        assert_eq!(Some(300.0), cache.document("focus").and_then(|d| d.focus().map(|f| f.power)));
+
+        handle.stop();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prime_the_cache_on_start() {
+        let dir = std::env::temp_dir().join(format!("tpvbc2http-prime-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("event.json"), r#"[{"name":"Race","laps":3}]"#).unwrap();
+
+        let cache = Cache::new();
+        let handle = BroadcastSource::new(&dir).start(&cache).unwrap();
+        assert_eq!(Some(r#"[{"name":"Race","laps":3}]"#), cache.get("event").as_deref());
+
+        handle.stop();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pick_up_missed_changes_on_rescan() {
+        let dir = std::env::temp_dir().join(format!("tpvbc2http-missed-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let focus = dir.join("focus.json");
+        fs::write(&focus, r#"[{"name":"Joe","power":200}]"#).unwrap();
+        let power = |cache: &Cache| cache.document("focus").and_then(|d| d.focus().map(|f| f.power));
+
+        let cache = Cache::new();
+        let mut source = BroadcastSource::new(&dir);
+        source.set_rescan_interval(Some(Duration::from_millis(300)));
+        let handle = source.start(&cache).unwrap();
+
+        // written in place and kept open, without the close event a reload waits for
+        let mut file = fs::File::options().write(true).open(&focus).unwrap();
+        io::Write::write_all(&mut file, br#"[{"name":"Joe","power":2000}]"#).unwrap();
+        file.sync_all().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        if cfg!(target_os = "linux") {
+            assert_eq!(Some(200.0), power(&cache));
+        }
+
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(Some(2000.0), power(&cache));
+
+        drop(file);
+        handle.stop();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn go_stale_despite_rescans() {
+        let dir = std::env::temp_dir().join(format!("tpvbc2http-rescan-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("focus.json"), r#"[{"name":"Joe","power":200}]"#).unwrap();
+
+        let cache = Cache::new();
+        cache.set_stale_after(StaleAfter { default: Some(Duration::from_millis(300)), ..StaleAfter::default() });
+        let mut source = BroadcastSource::new(&dir);
+        source.set_rescan_interval(Some(Duration::from_millis(50)));
+        let handle = source.start(&cache).unwrap();
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(!cache.is_stale("focus"));
+        thread::sleep(Duration::from_millis(400));
+        assert!(cache.is_stale("focus"));
+
+        // a write with the same content is a sign of life
+        fs::write(dir.join("focus.json"), r#"[{"name":"Joe","power":200}]"#).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(!cache.is_stale("focus"));
+
+        handle.stop();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compare_content_on_events() {
+        let dir = std::env::temp_dir().join(format!("tpvbc2http-coarse-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let cache = Cache::new();
+        let mut fingerprints = HashMap::new();
+        let focus = dir.join("focus.json");
+        fs::write(&focus, r#"[{"name":"Joe","power":200}]"#).unwrap();
+        BroadcastSource::reload(&cache, &mut fingerprints, &focus, true);
+        let modified = fs::metadata(&focus).unwrap().modified().unwrap();
+
+        // same length and modification time, as on a mount with coarse times
+        fs::write(&focus, r#"[{"name":"Joe","power":300}]"#).unwrap();
+        fs::File::options().write(true).open(&focus).unwrap().set_modified(modified).unwrap();
+
+        BroadcastSource::reload(&cache, &mut fingerprints, &focus, true);
+        assert_eq!(Some(200.0), cache.document("focus").and_then(|d| d.focus().map(|f| f.power)));
+        BroadcastSource::reload(&cache, &mut fingerprints, &focus, false);
+        assert_eq!(Some(300.0), cache.document("focus").and_then(|d| d.focus().map(|f| f.power)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pick_up_writes_by_polling() {
+        let dir = std::env::temp_dir().join(format!("tpvbc2http-polling-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let cache = Cache::new();
+        let mut source = BroadcastSource::new(&dir);
+        source.set_watcher(WatcherBackend::Polling(Duration::from_millis(50)));
+        source.set_debounce(Duration::ZERO);
+        let handle = source.start(&cache).unwrap();
+
+        fs::write(dir.join("focus.json"), r#"[{"name":"Joe","power":200}]"#).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(Some(200.0), cache.document("focus").and_then(|d| d.focus().map(|f| f.power)));
+
+        handle.stop();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wait_for_a_missing_or_recreated_directory() {
+        let dir = std::env::temp_dir().join(format!("tpvbc2http-missing-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let power = |cache: &Cache| cache.document("focus").and_then(|d| d.focus().map(|f| f.power));
+
+        let cache = Cache::new();
+        let mut source = BroadcastSource::new(&dir);
+        source.set_debounce(Duration::ZERO);
+        let handle = source.start(&cache).unwrap();
+        assert_eq!(WatcherState::Waiting, cache.watcher_state());
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("focus.json"), r#"[{"name":"Joe","power":200}]"#).unwrap();
+        thread::sleep(Duration::from_millis(1000));
+        assert_eq!(WatcherState::Watching, cache.watcher_state());
+        assert_eq!(Some(200.0), power(&cache));
+
+        fs::remove_dir_all(&dir).unwrap();
+        thread::sleep(Duration::from_millis(1000));
+        assert_eq!(WatcherState::Waiting, cache.watcher_state());
+        assert_eq!(Some(200.0), power(&cache));
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("focus.tmp"), r#"[{"name":"Joe","power":300}]"#).unwrap();
+        fs::rename(dir.join("focus.tmp"), dir.join("focus.json")).unwrap();
+        thread::sleep(Duration::from_millis(1000));
+        assert_eq!(Some(300.0), power(&cache));
+
+        fs::write(dir.join("focus.tmp"), r#"[{"name":"Joe","power":400}]"#).unwrap();
+        fs::rename(dir.join("focus.tmp"), dir.join("focus.json")).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(Some(400.0), power(&cache));
+
+        handle.stop();
+        assert_eq!(WatcherState::Stopped, cache.watcher_state());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ignore_our_own_reads() {
+        let event = |kind| Event::new(kind).add_path(PathBuf::from("focus.json"));
+
+        for kind in [AccessKind::Open(AccessMode::Read), AccessKind::Read, AccessKind::Close(AccessMode::Read)] {
+            assert!(BroadcastSource::changed_files(&event(EventKind::Access(kind)), false).is_empty());
+        }
+        assert_eq!(1, BroadcastSource::changed_files(&event(EventKind::Access(AccessKind::Close(AccessMode::Write))), false).len());
+
+        let renamed = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(PathBuf::from("focus.tmp"))
+            .add_path(PathBuf::from("focus.json"));
+        assert_eq!([PathBuf::from("focus.json")], BroadcastSource::changed_files(&renamed, false));
+    }
+}
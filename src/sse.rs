@@ -0,0 +1,60 @@
+use std::io::{self, Write};
+use std::sync::mpsc;
+use std::time::Duration;
+use crate::cache::{Cache, Update};
+
+/// Interval of keep-alive comments, also how fast a closed connection is noticed.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Write a single server-sent event, multi-line data is split into several data fields.
+pub fn write_event<W: Write>(w: &mut W, name: &str, data: &str) -> io::Result<()> {
+    let mut text = format!("event: {}\n", name);
+
+    for line in data.lines() {
+        text.push_str("data: ");
+        text.push_str(line);
+        text.push('\n');
+    }
+    text.push('\n');
+
+    w.write_all(text.as_bytes())?;
+    w.flush()
+}
+
+/// Stream the given topics until the client goes away.
+///
+/// The current content of every topic is sent first, then every update as it arrives.
+pub fn run<W: Write>(w: &mut W, cache: &Cache, topics: &[String], rx: mpsc::Receiver<Update>) -> io::Result<()> {
+    for topic in topics {
+        if let Some(content) = cache.get(topic) {
+            write_event(w, topic, &content)?;
+        }
+    }
+
+    loop {
+        match rx.recv_timeout(KEEPALIVE) {
+            Ok(update) => {
+                if topics.contains(&update.name) {
+                    write_event(w, &update.name, &update.content)?;
+                }
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                w.write_all(b": keepalive\n\n")?;
+                w.flush()?;
+            },
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod sse_should {
+    use super::*;
+
+    #[test]
+    fn split_multiline_data() {
+        let mut out = Vec::new();
+        write_event(&mut out, "event", "[\r\n  {}\r\n]").unwrap();
+        assert_eq!(b"event: event\ndata: [\ndata:   {}\ndata: ]\n\n", &out[..]);
+    }
+}
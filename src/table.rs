@@ -0,0 +1,123 @@
+//! Array-shaped documents as tables, e.g. to paste results into a spreadsheet.
+//!
+//! Every record is a row. Nested objects are flattened into columns named
+//! `parent.child`, nested arrays are kept as JSON text. Columns are ordered by
+//! their first appearance, so the order is the one of the document and stays
+//! the same while rows are added.
+
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Table {
+    pub columns: Vec<String>,
+    pub rows: Vec<Map<String, Value>>,
+}
+
+impl Table {
+    pub fn new() -> Table {
+        Table::default()
+    }
+
+    /// Table of a document, `None` if it is not an array of objects.
+    pub fn from_json(raw: &str) -> Option<Table> {
+        let records = match serde_json::from_str::<Value>(raw) {
+            Ok(Value::Array(records)) => records,
+            _ => return None,
+        };
+
+        let mut table = Table::new();
+        for record in &records {
+            if !record.is_object() {
+                return None;
+            }
+            table.add(&[], record);
+        }
+        Some(table)
+    }
+
+    /// Add a record as row, after the `leading` columns, e.g. a timestamp.
+    pub fn add(&mut self, leading: &[(&str, Value)], record: &Value) {
+        let mut row = Map::new();
+        for (column, value) in leading {
+            row.insert(column.to_string(), value.clone());
+        }
+        flatten("", record, &mut row);
+
+        for column in row.keys() {
+            if !self.columns.contains(column) {
+                self.columns.push(column.clone());
+            }
+        }
+        self.rows.push(row);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// RFC 4180 CSV with a header line, missing fields are empty.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::new();
+        push_line(&mut csv, self.columns.iter().map(|column| escape(column)));
+
+        for row in &self.rows {
+            push_line(&mut csv, self.columns.iter().map(|column| match row.get(column) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(text)) => escape(text),
+                Some(value) => escape(&value.to_string()),
+            }));
+        }
+        csv
+    }
+}
+
+fn flatten(prefix: &str, value: &Value, row: &mut Map<String, Value>) {
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                let column = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                flatten(&column, value, row);
+            }
+        },
+        value => {
+            row.insert(prefix.to_string(), value.clone());
+        },
+    }
+}
+
+fn push_line(csv: &mut String, fields: impl Iterator<Item = String>) {
+    csv.push_str(&fields.collect::<Vec<String>>().join(","));
+    csv.push_str("\r\n");
+}
+
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod table_should {
+    use super::*;
+
+    #[test]
+    fn flatten_records_in_document_order() {
+        let raw = r#"[{"name":"Joe, Jr.","position":1,"bike":{"frame":"aero"},"laps":[1,2]},{"position":2,"name":"Ann \"A\"","extra":true}]"#;
+        let table = Table::from_json(raw).unwrap();
+
+        assert_eq!(vec!["name", "position", "bike.frame", "laps", "extra"], table.columns);
+        assert_eq!(
+            "name,position,bike.frame,laps,extra\r\n\"Joe, Jr.\",1,aero,\"[1,2]\",\r\n\"Ann \"\"A\"\"\",2,,,true\r\n",
+            table.to_csv(),
+        );
+    }
+
+    #[test]
+    fn only_take_arrays_of_objects() {
+        assert!(Table::from_json(r#"{"a":1}"#).is_none());
+        assert!(Table::from_json("[1,2]").is_none());
+        assert!(Table::from_json("[]").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,58 @@
+//! Documents of several sources side by side, e.g. the focus record of every
+//! rider in a team time trial.
+
+use std::collections::BTreeMap;
+use serde_json::Value;
+use crate::cache::Cache;
+
+/// Route below /bcast/ for the aggregated documents, not usable as a source name.
+pub const TEAM: &str = "team";
+
+/// Records of document `name` of every source in one array, each with a
+/// `source` field naming where it came from.
+///
+/// `None` if no source has that document.
+pub fn aggregate(sources: &BTreeMap<String, Cache>, name: &str) -> Option<String> {
+    let mut found = false;
+    let mut records = Vec::new();
+
+    for (source, cache) in sources {
+        let raw = match cache.get(name) {
+            Some(raw) => raw,
+            None => continue,
+        };
+        found = true;
+
+        if let Ok(Value::Array(items)) = serde_json::from_str::<Value>(&raw) {
+            for mut item in items {
+                if let Value::Object(fields) = &mut item {
+                    fields.insert(String::from("source"), Value::String(source.clone()));
+                }
+                records.push(item);
+            }
+        }
+    }
+
+    found.then(|| Value::Array(records).to_string())
+}
+
+#[cfg(test)]
+mod team_should {
+    use super::*;
+
+    #[test]
+    fn tag_records_with_their_source() {
+        let mut sources = BTreeMap::new();
+        for (source, focus) in [("alice", r#"[{"power":250}]"#), ("bob", r#"[{"power":310}]"#), ("carol", "[]")] {
+            let cache = Cache::new();
+            cache.update("focus", String::from(focus));
+            sources.insert(String::from(source), cache);
+        }
+
+        let team: Value = serde_json::from_str(&aggregate(&sources, "focus").unwrap()).unwrap();
+        assert_eq!(2, team.as_array().unwrap().len());
+        assert_eq!("alice", team[0]["source"]);
+        assert_eq!(310, team[1]["power"]);
+        assert_eq!(None, aggregate(&sources, "laps"));
+    }
+}
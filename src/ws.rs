@@ -0,0 +1,112 @@
+use std::collections::HashSet;
+use std::io;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use serde_json::{json, Value};
+use simple_server::websocket::{Message, Sender, WebSocket};
+use crate::cache::{Cache, Update};
+
+/// Interval of pings sent to idle clients, also how fast a closed connection is noticed.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+type Topics = Arc<Mutex<HashSet<String>>>;
+
+/// Frame pushed to clients, the document is embedded as is.
+fn data_frame(topic: &str, content: &str) -> Message {
+    Message::Text(format!("{{\"topic\":{},\"data\":{}}}", json!(topic), content))
+}
+
+fn error_frame(error: &str) -> Message {
+    Message::Text(json!({ "error": error }).to_string())
+}
+
+/// Serve a client until it closes the connection.
+///
+/// Clients send `{"subscribe":["focus","groups"]}` or `{"unsubscribe":[...]}`
+/// and receive `{"topic":"focus","data":[...]}` for every update of a subscribed document.
+pub fn run(mut ws: WebSocket, cache: Cache) -> io::Result<()> {
+    let topics: Topics = Arc::new(Mutex::new(HashSet::new()));
+    let sender = ws.sender();
+
+    {
+        let sender = sender.clone();
+        let topics = topics.clone();
+        let rx = cache.subscribe();
+        thread::spawn(move || push(sender, topics, rx));
+    }
+
+    loop {
+        match ws.read() {
+            Ok(Message::Text(text)) => handle_request(&sender, &cache, &topics, &text)?,
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => (),
+            // the client went away without a close frame
+            Err(e) if matches!(e.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe) => return Ok(()),
+            Err(e) => {
+                // a protocol violation, closing also makes the push thread end on its next send
+                if e.kind() == io::ErrorKind::InvalidData {
+                    let _ = sender.close(1002);
+                }
+                return Err(e);
+            },
+        }
+    }
+}
+
+fn handle_request(sender: &Sender, cache: &Cache, topics: &Topics, text: &str) -> io::Result<()> {
+    let request: Value = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(e) => return sender.send(&error_frame(&format!("invalid request: {}", e))),
+    };
+
+    let names = |key: &str| -> Vec<String> {
+        match request.get(key).and_then(Value::as_array) {
+            Some(names) => names.iter().filter_map(Value::as_str).map(String::from).collect(),
+            None => Vec::new(),
+        }
+    };
+    let subscribe = names("subscribe");
+    let unsubscribe = names("unsubscribe");
+
+    if subscribe.is_empty() && unsubscribe.is_empty() {
+        return sender.send(&error_frame("expected subscribe or unsubscribe"));
+    }
+
+    for topic in unsubscribe {
+        topics.lock().unwrap().remove(&topic);
+    }
+
+    for topic in subscribe {
+        match cache.get(&topic) {
+            Some(content) => {
+                if topics.lock().unwrap().insert(topic.clone()) {
+                    sender.send(&data_frame(&topic, &content))?;
+                }
+            },
+            None => sender.send(&error_frame(&format!("unknown topic: {}", topic)))?,
+        }
+    }
+
+    Ok(())
+}
+
+fn push(sender: Sender, topics: Topics, rx: mpsc::Receiver<Update>) {
+    loop {
+        let result = match rx.recv_timeout(PING_INTERVAL) {
+            Ok(update) => {
+                if topics.lock().unwrap().contains(&update.name) {
+                    sender.send(&data_frame(&update.name, &update.content))
+                } else {
+                    Ok(())
+                }
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => sender.send(&Message::Ping(Vec::new())),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        };
+
+        if result.is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use tpvbc2http::Instance;
+
+fn connect(addr: SocketAddr, path: &str) -> BufReader<TcpStream> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\n\r\n", path).unwrap();
+    BufReader::new(stream)
+}
+
+fn read_line(reader: &mut BufReader<TcpStream>) -> String {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    line.trim_end().to_string()
+}
+
+// status line and headers
+fn read_head(reader: &mut BufReader<TcpStream>) -> String {
+    let status = read_line(reader);
+    while !read_line(reader).is_empty() {}
+    status
+}
+
+// next event as name and data, keep-alive comments are skipped
+fn read_event(reader: &mut BufReader<TcpStream>) -> (String, String) {
+    let (mut name, mut data) = (String::new(), Vec::new());
+    loop {
+        let line = read_line(reader);
+        if let Some(value) = line.strip_prefix("event: ") {
+            name = value.to_string();
+        } else if let Some(value) = line.strip_prefix("data: ") {
+            data.push(value.to_string());
+        } else if line.is_empty() && !name.is_empty() {
+            return (name, data.join("\n"));
+        }
+    }
+}
+
+#[test]
+fn test_stream_snapshot_and_updates() {
+    let dir = std::env::temp_dir().join(format!("tpvbc2http-sse-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("focus.json"), r#"[{"name":"A"}]"#).unwrap();
+    fs::write(dir.join("event.json"), r#"[{"name":"Race"}]"#).unwrap();
+
+    let handle = Instance::new().start("127.0.0.1", "0", Some(dir.to_str().unwrap().to_string())).unwrap();
+    let cache = handle.cache().clone();
+
+    // everything first, in the order of the index
+    let mut all = connect(handle.local_addr(), "/bcast/stream");
+    assert_eq!("HTTP/1.1 200 OK", read_head(&mut all));
+    let names: Vec<String> = (0..cache.names().len()).map(|_| read_event(&mut all).0).collect();
+    assert_eq!(cache.names(), names);
+
+    let mut focus = connect(handle.local_addr(), "/bcast/stream?topics=focus");
+    assert_eq!("HTTP/1.1 200 OK", read_head(&mut focus));
+    assert_eq!((String::from("focus"), String::from(r#"[{"name":"A"}]"#)), read_event(&mut focus));
+
+    // only the topics asked for
+    cache.update("event", String::from(r#"[{"name":"Finale"}]"#));
+    cache.update("focus", String::from(r#"[{"name":"B"}]"#));
+    assert_eq!((String::from("focus"), String::from(r#"[{"name":"B"}]"#)), read_event(&mut focus));
+
+    let mut unknown = connect(handle.local_addr(), "/bcast/stream?topics=focus,laps");
+    assert_eq!("HTTP/1.1 400 Bad Request", read_head(&mut unknown));
+
+    drop((all, focus, unknown));
+    handle.shutdown();
+    handle.wait();
+    fs::remove_dir_all(&dir).unwrap();
+}